@@ -0,0 +1,1045 @@
+use crate::manifest::{ManifestResult, ManifestRow};
+use crate::name;
+use crate::price::PriceProvider;
+use crate::signer::Role;
+use crate::transaction::{
+    send_instruction_groups, send_legacy_instruction_groups, send_legacy_transaction,
+    send_legacy_transaction_with_signers, SendOptions, SendSummary,
+};
+use crate::utils::{self, ReferralTokenBalance};
+use anchor_lang::{AccountDeserialize, InstructionData, ToAccountMetas};
+use anchor_spl::associated_token::get_associated_token_address_with_program_id;
+use anchor_spl::associated_token::spl_associated_token_account::instruction::create_associated_token_account_idempotent;
+use anchor_spl::token::spl_token::amount_to_ui_amount_string_trimmed;
+use anyhow::Context;
+use referral::accounts as referral_accounts;
+use referral::instruction as referral_instructions;
+use referral::{
+    InitializeReferralAccountParams, InitializeReferralAccountWithNameParams,
+    UpdateReferralAccountParams,
+};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::instruction::Instruction;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signature};
+use solana_sdk::signer::Signer;
+use solana_sdk::system_program;
+use std::sync::Arc;
+
+/// Max share of the fees a referral account can get, in basis points
+pub const MAX_SHARE_BPS: u16 = 10_000;
+/// How many accounts each init-referral-account instruction needs
+pub const INIT_REFERRAL_ACCOUNT_ACCOUNTS_LEN: usize = 5;
+/// Max number of referral accounts created or updated per transaction, bounded by the
+/// names in the instruction data rather than accounts
+pub const MAX_REFERRAL_ACCOUNTS_PER_TRANSACTION: usize = 8;
+/// How many accounts each init-token-account instruction needs
+pub const INIT_REFERRAL_ATA_ACCOUNTS_LEN: usize = 7;
+/// Max number of claims per transaction, bounded by compute rather than accounts
+pub const MAX_CLAIMS_PER_TRANSACTION: usize = 8;
+
+/// Builds the instructions of the referral program and fetches its accounts.
+///
+/// Instruction builders only derive addresses and never touch the network; the methods
+/// that need chain state go through the given `RpcClient`, which can be a mock.
+#[derive(Clone)]
+pub struct ReferralClient {
+    rpc_client: Arc<RpcClient>,
+    program: Pubkey,
+}
+
+/// The instructions creating the missing referral token accounts of a list of mints
+#[derive(Debug, Clone, Default)]
+pub struct ReferralTokenAccountInstructions {
+    /// The mints whose token account is missing, with the instruction creating it
+    pub instructions: Vec<(Pubkey, Instruction)>,
+    /// The mints whose token account already exists
+    pub existing: Vec<Pubkey>,
+    /// The mints that are missing or not token mints, with the reason
+    pub invalid: Vec<(Pubkey, String)>,
+}
+
+/// The outcome of [`ReferralClient::create_referral_accounts_from_manifest`]
+#[derive(Debug, Default)]
+pub struct ManifestOutcome {
+    /// One result per manifest row, in the same order
+    pub results: Vec<ManifestResult>,
+    /// The transactions creating the missing accounts
+    pub created: SendSummary,
+    /// The transactions setting the shares of the created accounts
+    pub updated: SendSummary,
+    /// How many rows already had their account
+    pub skipped: usize,
+    /// How many created accounts were left with the project default share
+    pub failed_shares: usize,
+    /// How many shares a dry run would set once the accounts are created
+    pub pending_shares: usize,
+}
+
+impl ManifestOutcome {
+    pub fn ensure_succeeded(&self) -> anyhow::Result<()> {
+        if !self.created.failed.is_empty() || self.failed_shares > 0 {
+            anyhow::bail!(
+                "failed to create {} referral accounts and to set {} shares",
+                self.created.failed.len(),
+                self.failed_shares
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The outcome of [`ReferralClient::create_missing_referral_token_accounts`]
+#[derive(Debug, Default)]
+pub struct ReferralTokenAccountsCreation {
+    /// The mints whose token account was missing, with one group each in `summary`
+    pub missing: Vec<Pubkey>,
+    /// The mints whose token account already exists
+    pub existing: Vec<Pubkey>,
+    /// The mints that are missing or not token mints, with the reason
+    pub invalid: Vec<(Pubkey, String)>,
+    pub summary: SendSummary,
+}
+
+/// A referral token account holding fees to claim
+#[derive(Debug, Clone, Copy)]
+pub struct Claimable {
+    pub mint: Pubkey,
+    pub referral_token_account: Pubkey,
+    pub token_program: Pubkey,
+    pub amount: u64,
+    pub decimals: u8,
+}
+
+/// The outcome of [`ReferralClient::claim_all`]
+#[derive(Debug, Default)]
+pub struct ClaimAll {
+    /// Every referral token account that held fees
+    pub claims: Vec<Claimable>,
+    /// The claim transactions, with one group per claim
+    pub summary: SendSummary,
+}
+
+/// The USD value of the fees held by a referral account
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// One row per existing referral token account
+    pub rows: Vec<ReportRow>,
+    /// The value of the priced rows
+    pub total_usd: f64,
+}
+
+impl Report {
+    /// The mints without a known price, left out of the total
+    pub fn unpriced(&self) -> Vec<Pubkey> {
+        self.rows
+            .iter()
+            .filter(|row| row.price_usd.is_none())
+            .map(|row| row.mint)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReportRow {
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub decimals: u8,
+    pub price_usd: Option<f64>,
+    pub value_usd: Option<f64>,
+}
+
+/// A referral account before and after a transaction changed it. In dry-run and sign-only
+/// mode, `after` is the expected account rather than the one on chain.
+#[derive(Clone)]
+pub struct ReferralAccountChange {
+    pub before: referral::ReferralAccount,
+    pub after: referral::ReferralAccount,
+    pub signature: Signature,
+}
+
+impl ReferralAccountChange {
+    /// Every field of the account, rendered before and after the change
+    pub fn diff(&self) -> Vec<FieldDiff> {
+        let (before, after) = (&self.before, &self.after);
+        vec![
+            FieldDiff::new("partner", before.partner, after.partner),
+            FieldDiff::new("project", before.project, after.project),
+            FieldDiff::new("share_bps", before.share_bps, after.share_bps),
+            FieldDiff {
+                field: "name",
+                before: format!("{:?}", before.name),
+                after: format!("{:?}", after.name),
+            },
+        ]
+    }
+}
+
+/// A field of an account, rendered before and after a change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    pub before: String,
+    pub after: String,
+}
+
+impl FieldDiff {
+    fn new(field: &'static str, before: impl ToString, after: impl ToString) -> Self {
+        FieldDiff {
+            field,
+            before: before.to_string(),
+            after: after.to_string(),
+        }
+    }
+
+    pub fn changed(&self) -> bool {
+        self.before != self.after
+    }
+}
+
+impl ReferralClient {
+    pub fn new(rpc_client: Arc<RpcClient>, program: Pubkey) -> Self {
+        ReferralClient {
+            rpc_client,
+            program,
+        }
+    }
+
+    pub fn rpc_client(&self) -> &RpcClient {
+        &self.rpc_client
+    }
+
+    pub fn program(&self) -> Pubkey {
+        self.program
+    }
+
+    /// The PDA of the referral account of `project` named `name`, failing for names that
+    /// [`name::validate`] rejects
+    pub fn find_referral_account(&self, project: Pubkey, name: &str) -> anyhow::Result<Pubkey> {
+        let name = name::validate(name, false)?;
+        Ok(utils::find_referral_account(self.program, project, &name))
+    }
+
+    pub fn find_referral_token_account(&self, referral_account: Pubkey, mint: Pubkey) -> Pubkey {
+        utils::find_referral_token_account(self.program, referral_account, mint)
+    }
+
+    /// Create an unnamed referral account at the address of a new keypair, which must sign
+    pub fn create_referral_account(
+        &self,
+        payer: Pubkey,
+        partner: Pubkey,
+        project: Pubkey,
+        referral_account: Pubkey,
+    ) -> Instruction {
+        Instruction::new_with_bytes(
+            self.program,
+            &referral_instructions::InitializeReferralAccount {
+                params: InitializeReferralAccountParams {},
+            }
+            .data(),
+            referral_accounts::InitializeReferralAccount {
+                payer,
+                partner,
+                project,
+                referral_account,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+        )
+    }
+
+    /// Create the referral account of `project` named `name`, returned with its PDA. The
+    /// name is checked and normalized with [`name::validate`] first.
+    pub fn create_referral_account_with_name(
+        &self,
+        payer: Pubkey,
+        partner: Pubkey,
+        project: Pubkey,
+        name: &str,
+    ) -> anyhow::Result<(Instruction, Pubkey)> {
+        let name = name::validate(name, false)?;
+        let referral_account = utils::find_referral_account(self.program, project, &name);
+        let instruction = Instruction::new_with_bytes(
+            self.program,
+            &referral_instructions::InitializeReferralAccountWithName {
+                params: InitializeReferralAccountWithNameParams { name },
+            }
+            .data(),
+            referral_accounts::InitializeReferralAccountWithName {
+                payer,
+                partner,
+                project,
+                referral_account,
+                system_program: system_program::ID,
+            }
+            .to_account_metas(None),
+        );
+        Ok((instruction, referral_account))
+    }
+
+    pub fn create_referral_token_account(
+        &self,
+        payer: Pubkey,
+        project: Pubkey,
+        referral_account: Pubkey,
+        mint: Pubkey,
+        token_program: Pubkey,
+    ) -> Instruction {
+        Instruction::new_with_bytes(
+            self.program,
+            &referral_instructions::InitializeReferralTokenAccount.data(),
+            referral_accounts::InitializeReferralTokenAccount {
+                payer,
+                project,
+                referral_account,
+                referral_token_account: self.find_referral_token_account(referral_account, mint),
+                mint,
+                system_program: system_program::ID,
+                token_program,
+            }
+            .to_account_metas(None),
+        )
+    }
+
+    /// Create the referral token accounts of `mints` that do not exist yet, each with the
+    /// token program of its mint. Mints that are missing or not token mints are left out
+    /// and listed in `invalid`.
+    pub async fn create_referral_token_accounts(
+        &self,
+        payer: Pubkey,
+        project: Pubkey,
+        referral_account: Pubkey,
+        mints: &[Pubkey],
+    ) -> anyhow::Result<ReferralTokenAccountInstructions> {
+        let referral_token_accounts = mints
+            .iter()
+            .map(|mint| self.find_referral_token_account(referral_account, *mint))
+            .collect::<Vec<_>>();
+        let exists =
+            utils::fetch_accounts_exist(&self.rpc_client, &referral_token_accounts).await?;
+        let (existing, missing): (Vec<_>, Vec<_>) =
+            mints.iter().zip(exists).partition(|(_, exists)| *exists);
+        let missing = missing
+            .into_iter()
+            .map(|(mint, _)| *mint)
+            .collect::<Vec<_>>();
+        let mint_infos = utils::try_fetch_mints(&self.rpc_client, &missing).await?;
+
+        let mut instructions = vec![];
+        let mut invalid = vec![];
+        for (mint, mint_info) in missing.into_iter().zip(mint_infos) {
+            match mint_info {
+                Ok(mint_info) => instructions.push((
+                    mint,
+                    self.create_referral_token_account(
+                        payer,
+                        project,
+                        referral_account,
+                        mint,
+                        mint_info.token_program,
+                    ),
+                )),
+                Err(err) => invalid.push((mint, format!("{:#}", err))),
+            }
+        }
+        Ok(ReferralTokenAccountInstructions {
+            instructions,
+            existing: existing.into_iter().map(|(mint, _)| *mint).collect(),
+            invalid,
+        })
+    }
+
+    /// Create the referral token accounts of `mints` that do not exist yet, in lookup table
+    /// transactions when they do not fit a legacy one
+    pub async fn create_missing_referral_token_accounts(
+        &self,
+        payer: &Keypair,
+        project: Pubkey,
+        referral_account: Pubkey,
+        mints: &[Pubkey],
+        send_options: &SendOptions,
+    ) -> anyhow::Result<ReferralTokenAccountsCreation> {
+        let prepared = self
+            .create_referral_token_accounts(payer.pubkey(), project, referral_account, mints)
+            .await?;
+        let (missing, groups): (Vec<_>, Vec<_>) = prepared
+            .instructions
+            .into_iter()
+            .map(|(mint, instruction)| (mint, vec![instruction]))
+            .unzip();
+        let summary = send_instruction_groups(
+            &self.rpc_client,
+            payer,
+            send_options,
+            groups,
+            INIT_REFERRAL_ATA_ACCOUNTS_LEN,
+            utils::MAX_LUT_SIZE / INIT_REFERRAL_ATA_ACCOUNTS_LEN,
+        )
+        .await;
+        Ok(ReferralTokenAccountsCreation {
+            missing,
+            existing: prepared.existing,
+            invalid: prepared.invalid,
+            summary,
+        })
+    }
+
+    /// Set the share of a referral account, signed by the project admin
+    pub fn update_referral_account(
+        &self,
+        admin: Pubkey,
+        project: Pubkey,
+        referral_account: Pubkey,
+        share_bps: u16,
+    ) -> Instruction {
+        Instruction::new_with_bytes(
+            self.program,
+            &referral_instructions::UpdateReferralAccount {
+                params: UpdateReferralAccountParams { share_bps },
+            }
+            .data(),
+            referral_accounts::UpdateReferralAccount {
+                admin,
+                project,
+                referral_account,
+            }
+            .to_account_metas(None),
+        )
+    }
+
+    /// Hand the partner role of a referral account to `new_partner`, signed by the partner
+    pub fn transfer_referral_account(
+        &self,
+        partner: Pubkey,
+        new_partner: Pubkey,
+        project: Pubkey,
+        referral_account: Pubkey,
+    ) -> Instruction {
+        Instruction::new_with_bytes(
+            self.program,
+            &referral_instructions::TransferReferralAccount.data(),
+            referral_accounts::TransferReferralAccount {
+                partner,
+                new_partner,
+                project,
+                referral_account,
+            }
+            .to_account_metas(None),
+        )
+    }
+
+    /// Claim the fees of a referral token account, creating the partner and admin token
+    /// accounts first when needed
+    #[allow(clippy::too_many_arguments)]
+    pub fn claim(
+        &self,
+        payer: Pubkey,
+        mint: Pubkey,
+        token_program: Pubkey,
+        project: Pubkey,
+        admin: Pubkey,
+        referral_account: Pubkey,
+        partner: Pubkey,
+    ) -> Vec<Instruction> {
+        let partner_token_account =
+            get_associated_token_address_with_program_id(&partner, &mint, &token_program);
+        let project_admin_token_account =
+            get_associated_token_address_with_program_id(&admin, &mint, &token_program);
+        let accounts = referral_accounts::Claim {
+            payer,
+            project,
+            admin,
+            project_admin_token_account,
+            referral_account,
+            referral_token_account: self.find_referral_token_account(referral_account, mint),
+            partner,
+            partner_token_account,
+            mint,
+            associated_token_program: anchor_spl::associated_token::ID,
+            system_program: system_program::ID,
+            token_program,
+        }
+        .to_account_metas(None);
+
+        vec![
+            create_associated_token_account_idempotent(&payer, &partner, &mint, &token_program),
+            create_associated_token_account_idempotent(&payer, &admin, &mint, &token_program),
+            Instruction::new_with_bytes(
+                self.program,
+                &referral_instructions::Claim.data(),
+                accounts,
+            ),
+        ]
+    }
+
+    pub async fn fetch_referral_account(
+        &self,
+        address: Pubkey,
+    ) -> anyhow::Result<referral::ReferralAccount> {
+        let data = self.rpc_client.get_account_data(&address).await?;
+        Ok(referral::ReferralAccount::try_deserialize(&mut &data[..])?)
+    }
+
+    pub async fn fetch_project(&self, address: Pubkey) -> anyhow::Result<referral::Project> {
+        let data = self.rpc_client.get_account_data(&address).await?;
+        Ok(referral::Project::try_deserialize(&mut &data[..])?)
+    }
+
+    /// Fetch the referral accounts of `partner` and/or `project`, or every one without either
+    pub async fn fetch_referral_accounts(
+        &self,
+        partner: Option<Pubkey>,
+        project: Option<Pubkey>,
+    ) -> anyhow::Result<Vec<(Pubkey, referral::ReferralAccount)>> {
+        utils::fetch_referral_accounts(&self.rpc_client, self.program, partner, project).await
+    }
+
+    /// Count the referral accounts [`Self::fetch_referral_accounts`] would return, without
+    /// downloading their data
+    pub async fn count_referral_accounts(
+        &self,
+        partner: Option<Pubkey>,
+        project: Option<Pubkey>,
+    ) -> anyhow::Result<usize> {
+        utils::count_referral_accounts(&self.rpc_client, self.program, partner, project).await
+    }
+
+    /// The balances of the referral token accounts of `referral_account`, a referral account
+    /// of `project`, either for every mint in `mints` or for every one found on chain
+    pub async fn fetch_referral_token_balances(
+        &self,
+        project: Pubkey,
+        referral_account: Pubkey,
+        mints: Option<&[Pubkey]>,
+    ) -> anyhow::Result<Vec<ReferralTokenBalance>> {
+        utils::fetch_referral_token_balances(
+            &self.rpc_client,
+            self.program,
+            project,
+            referral_account,
+            mints,
+        )
+        .await
+    }
+
+    /// Create the referral accounts of the manifest `rows` that do not exist yet, then set
+    /// the shares other than the project default. Accounts are created with the default
+    /// share, so other shares need `admin` to be the project admin, and are only set once
+    /// the accounts exist, which dry runs cannot simulate.
+    pub async fn create_referral_accounts_from_manifest(
+        &self,
+        payer: &Keypair,
+        admin: Option<&Keypair>,
+        project_key: Pubkey,
+        rows: &[ManifestRow],
+        send_options: &SendOptions,
+    ) -> anyhow::Result<ManifestOutcome> {
+        let project = self.fetch_project(project_key).await?;
+        for row in rows {
+            if row
+                .share_bps
+                .is_some_and(|share_bps| share_bps > MAX_SHARE_BPS)
+            {
+                anyhow::bail!(
+                    "{}: share_bps must be between 0 and {}",
+                    row.name,
+                    MAX_SHARE_BPS
+                );
+            }
+        }
+        let custom_share = |row: &ManifestRow| {
+            row.share_bps
+                .filter(|share_bps| *share_bps != project.default_share_bps)
+        };
+        let admin = if rows.iter().any(|row| custom_share(row).is_some()) {
+            let admin = admin.filter(|admin| admin.pubkey() == project.admin);
+            Some(admin.with_context(|| {
+                format!(
+                    "setting shares other than the default needs the project admin {} as keypair",
+                    project.admin
+                )
+            })?)
+        } else {
+            None
+        };
+
+        let referral_accounts = rows
+            .iter()
+            .map(|row| self.find_referral_account(project_key, &row.name))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let exists = utils::fetch_accounts_exist(&self.rpc_client, &referral_accounts).await?;
+        let missing = (0..rows.len())
+            .filter(|index| !exists[*index])
+            .collect::<Vec<_>>();
+
+        let groups = missing
+            .iter()
+            .map(|index| {
+                let (instruction, _) = self.create_referral_account_with_name(
+                    payer.pubkey(),
+                    rows[*index].partner,
+                    project_key,
+                    &rows[*index].name,
+                )?;
+                Ok(vec![instruction])
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let created = send_legacy_instruction_groups(
+            &self.rpc_client,
+            payer,
+            send_options,
+            groups,
+            MAX_REFERRAL_ACCOUNTS_PER_TRANSACTION,
+        )
+        .await;
+
+        let to_update = created
+            .succeeded
+            .iter()
+            .map(|group| missing[*group])
+            .filter_map(|index| custom_share(&rows[index]).map(|share_bps| (index, share_bps)))
+            .collect::<Vec<_>>();
+        let updated = match admin {
+            Some(admin) if !send_options.dry_run && !to_update.is_empty() => {
+                let groups = to_update
+                    .iter()
+                    .map(|(index, share_bps)| {
+                        vec![self.update_referral_account(
+                            admin.pubkey(),
+                            project_key,
+                            referral_accounts[*index],
+                            *share_bps,
+                        )]
+                    })
+                    .collect::<Vec<_>>();
+                send_legacy_instruction_groups(
+                    &self.rpc_client,
+                    admin,
+                    send_options,
+                    groups,
+                    MAX_REFERRAL_ACCOUNTS_PER_TRANSACTION,
+                )
+                .await
+            }
+            _ => SendSummary::default(),
+        };
+
+        let mut results = rows
+            .iter()
+            .zip(&referral_accounts)
+            .map(|(row, referral_account)| ManifestResult {
+                name: row.name.clone(),
+                partner: row.partner.to_string(),
+                referral_account: referral_account.to_string(),
+                status: "exists",
+                signature: None,
+                share_bps: None,
+                share_signature: None,
+            })
+            .collect::<Vec<_>>();
+        for (group, index) in missing.iter().enumerate() {
+            let signature = created.signature_by_group.get(&group);
+            results[*index].status = if signature.is_some() {
+                "created"
+            } else {
+                "failed"
+            };
+            results[*index].signature = signature.map(ToString::to_string);
+        }
+        for (group, (index, share_bps)) in to_update.iter().enumerate() {
+            results[*index].share_bps = Some(*share_bps);
+            results[*index].share_signature = updated
+                .signature_by_group
+                .get(&group)
+                .map(ToString::to_string);
+        }
+        let (failed_shares, pending_shares) = if send_options.dry_run {
+            (0, to_update.len())
+        } else {
+            (to_update.len() - updated.succeeded.len(), 0)
+        };
+
+        Ok(ManifestOutcome {
+            results,
+            skipped: rows.len() - missing.len(),
+            created,
+            updated,
+            failed_shares,
+            pending_shares,
+        })
+    }
+
+    /// Set the share of a referral account, as its project admin
+    pub async fn update_share(
+        &self,
+        admin: &Keypair,
+        referral_account: Pubkey,
+        share_bps: u16,
+        send_options: &SendOptions,
+    ) -> anyhow::Result<ReferralAccountChange> {
+        if share_bps > MAX_SHARE_BPS {
+            anyhow::bail!(
+                "share_bps must be between 0 and {}, got {}",
+                MAX_SHARE_BPS,
+                share_bps
+            );
+        }
+        let before = self.fetch_referral_account(referral_account).await?;
+        let project = self.fetch_project(before.project).await?;
+        if project.admin != admin.pubkey() {
+            anyhow::bail!(
+                "referral accounts of project {} can only be updated by its admin {}",
+                before.project,
+                project.admin
+            );
+        }
+
+        let instruction = self.update_referral_account(
+            admin.pubkey(),
+            before.project,
+            referral_account,
+            share_bps,
+        );
+        let signature =
+            send_legacy_transaction(&self.rpc_client, admin, send_options, &[instruction]).await?;
+
+        let after = if !send_options.submits() {
+            referral::ReferralAccount {
+                share_bps,
+                ..before.clone()
+            }
+        } else {
+            self.fetch_referral_account(referral_account).await?
+        };
+        Ok(ReferralAccountChange {
+            before,
+            after,
+            signature,
+        })
+    }
+
+    /// Hand a referral account over to `new_partner`, signed by its current `partner` and
+    /// paid by `payer`
+    pub async fn transfer(
+        &self,
+        payer: &Keypair,
+        partner: &Role,
+        referral_account: Pubkey,
+        new_partner: Pubkey,
+        send_options: &SendOptions,
+    ) -> anyhow::Result<ReferralAccountChange> {
+        let before = self.fetch_referral_account(referral_account).await?;
+        if before.partner != partner.pubkey() {
+            anyhow::bail!(
+                "referral account {} can only be transferred by its partner {}",
+                referral_account,
+                before.partner
+            );
+        }
+
+        let instruction = self.transfer_referral_account(
+            partner.pubkey(),
+            new_partner,
+            before.project,
+            referral_account,
+        );
+        let mut keypairs = vec![payer];
+        keypairs.extend(partner.keypair());
+        let signature = send_legacy_transaction_with_signers(
+            &self.rpc_client,
+            &payer.pubkey(),
+            &keypairs,
+            send_options,
+            &[instruction],
+        )
+        .await?;
+
+        let after = if !send_options.submits() {
+            referral::ReferralAccount {
+                partner: new_partner,
+                ..before.clone()
+            }
+        } else {
+            let after = self.fetch_referral_account(referral_account).await?;
+            if after.partner != new_partner {
+                anyhow::bail!(
+                    "referral account {} still has partner {} after the transfer",
+                    referral_account,
+                    after.partner
+                );
+            }
+            after
+        };
+        Ok(ReferralAccountChange {
+            before,
+            after,
+            signature,
+        })
+    }
+
+    /// Claim the fees of `referral_account` in each of `mints`, with one group per mint in
+    /// the returned summary
+    pub async fn claim_mints(
+        &self,
+        payer: &Keypair,
+        referral_account: Pubkey,
+        mints: &[Pubkey],
+        send_options: &SendOptions,
+    ) -> anyhow::Result<SendSummary> {
+        let mint_infos = utils::fetch_mints(&self.rpc_client, mints).await?;
+        let mints = mints
+            .iter()
+            .zip(mint_infos)
+            .map(|(mint, mint_info)| (*mint, mint_info.token_program))
+            .collect::<Vec<_>>();
+        self.send_claims(payer, referral_account, &mints, send_options)
+            .await
+    }
+
+    /// The referral token accounts of `referral_account` holding fees, under both token
+    /// programs
+    pub async fn fetch_claimable(
+        &self,
+        referral_account: Pubkey,
+    ) -> anyhow::Result<Vec<Claimable>> {
+        let referral = self.fetch_referral_account(referral_account).await?;
+        let token_accounts = utils::fetch_referral_token_accounts(
+            &self.rpc_client,
+            self.program,
+            referral.project,
+            referral_account,
+        )
+        .await?
+        .into_iter()
+        .filter(|token_account| token_account.amount > 0)
+        .collect::<Vec<_>>();
+        let mints = token_accounts
+            .iter()
+            .map(|token_account| token_account.mint)
+            .collect::<Vec<_>>();
+        let mint_infos = utils::fetch_mints(&self.rpc_client, &mints).await?;
+
+        Ok(token_accounts
+            .into_iter()
+            .zip(mint_infos)
+            .map(|(token_account, mint_info)| Claimable {
+                mint: token_account.mint,
+                referral_token_account: token_account.address,
+                token_program: token_account.token_program,
+                amount: token_account.amount,
+                decimals: mint_info.decimals,
+            })
+            .collect())
+    }
+
+    /// Claim the fees of every referral token account of `referral_account` holding some,
+    /// reporting them to the progress of `send_options` first
+    pub async fn claim_all(
+        &self,
+        payer: &Keypair,
+        referral_account: Pubkey,
+        send_options: &SendOptions,
+    ) -> anyhow::Result<ClaimAll> {
+        let claims = self.fetch_claimable(referral_account).await?;
+        let progress = &send_options.progress;
+        if claims.is_empty() {
+            progress.status("Nothing to claim");
+            return Ok(ClaimAll::default());
+        }
+
+        progress.status(&format!(
+            "Claiming from {} referral token accounts:",
+            claims.len()
+        ));
+        for claim in &claims {
+            progress.status(&format!(
+                "  {}: {}",
+                claim.mint,
+                amount_to_ui_amount_string_trimmed(claim.amount, claim.decimals)
+            ));
+        }
+        let mints = claims
+            .iter()
+            .map(|claim| (claim.mint, claim.token_program))
+            .collect::<Vec<_>>();
+        let summary = self
+            .send_claims(payer, referral_account, &mints, send_options)
+            .await?;
+        Ok(ClaimAll { claims, summary })
+    }
+
+    /// Send the claims of `mints`, each given with its token program
+    async fn send_claims(
+        &self,
+        payer: &Keypair,
+        referral_account: Pubkey,
+        mints: &[(Pubkey, Pubkey)],
+        send_options: &SendOptions,
+    ) -> anyhow::Result<SendSummary> {
+        let referral = self.fetch_referral_account(referral_account).await?;
+        let project = self.fetch_project(referral.project).await?;
+        let groups = mints
+            .iter()
+            .map(|(mint, token_program)| {
+                self.claim(
+                    payer.pubkey(),
+                    *mint,
+                    *token_program,
+                    referral.project,
+                    project.admin,
+                    referral_account,
+                    referral.partner,
+                )
+            })
+            .collect::<Vec<_>>();
+        Ok(send_legacy_instruction_groups(
+            &self.rpc_client,
+            payer,
+            send_options,
+            groups,
+            MAX_CLAIMS_PER_TRANSACTION,
+        )
+        .await)
+    }
+
+    /// Value the existing referral token accounts of `referral_account`, either those of
+    /// `mints` or every one found on chain, at the prices of `price_provider`
+    pub async fn report(
+        &self,
+        referral_account: Pubkey,
+        mints: Option<&[Pubkey]>,
+        price_provider: &dyn PriceProvider,
+    ) -> anyhow::Result<Report> {
+        let account = self.fetch_referral_account(referral_account).await?;
+        let balances = utils::fetch_referral_token_balances(
+            &self.rpc_client,
+            self.program,
+            account.project,
+            referral_account,
+            mints,
+        )
+        .await?
+        .into_iter()
+        .filter_map(|balance| balance.amount.map(|amount| (balance, amount)))
+        .collect::<Vec<_>>();
+        let prices = price_provider
+            .prices(
+                &balances
+                    .iter()
+                    .map(|(balance, _)| balance.mint)
+                    .collect::<Vec<_>>(),
+            )
+            .await?;
+
+        let mut report = Report::default();
+        for (balance, amount) in balances {
+            let ui_amount = amount as f64 / 10f64.powi(balance.decimals as i32);
+            let price_usd = prices.get(&balance.mint).copied();
+            let value_usd = price_usd.map(|price| price * ui_amount);
+            report.total_usd += value_usd.unwrap_or_default();
+            report.rows.push(ReportRow {
+                mint: balance.mint,
+                amount,
+                decimals: balance.decimals,
+                price_usd,
+                value_usd,
+            });
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> ReferralClient {
+        ReferralClient::new(
+            Arc::new(RpcClient::new_mock("succeeds".to_string())),
+            Pubkey::new_unique(),
+        )
+    }
+
+    #[test]
+    fn named_account_is_created_at_its_pda() {
+        let client = client();
+        let (payer, partner, project) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let (instruction, referral_account) = client
+            .create_referral_account_with_name(payer, partner, project, "alice")
+            .unwrap();
+
+        assert_eq!(
+            referral_account,
+            client.find_referral_account(project, "alice").unwrap()
+        );
+        assert_eq!(instruction.program_id, client.program());
+        assert_eq!(
+            instruction.accounts.len(),
+            INIT_REFERRAL_ACCOUNT_ACCOUNTS_LEN
+        );
+        assert!(instruction
+            .accounts
+            .iter()
+            .any(|meta| meta.pubkey == referral_account && meta.is_writable && !meta.is_signer));
+    }
+
+    #[test]
+    fn rejects_names_that_cannot_be_seeds() {
+        let client = client();
+        let (payer, partner, project) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let long_name = "a".repeat(name::MAX_NAME_LEN + 1);
+
+        assert!(client.find_referral_account(project, &long_name).is_err());
+        assert!(client
+            .create_referral_account_with_name(payer, partner, project, &long_name)
+            .is_err());
+        assert!(client
+            .create_referral_account_with_name(payer, partner, project, "")
+            .is_err());
+    }
+
+    #[test]
+    fn unnamed_account_signs_for_its_address() {
+        let client = client();
+        let referral_account = Pubkey::new_unique();
+        let instruction = client.create_referral_account(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            referral_account,
+        );
+
+        assert!(instruction
+            .accounts
+            .iter()
+            .any(|meta| meta.pubkey == referral_account && meta.is_signer));
+    }
+
+    #[test]
+    fn claim_creates_token_accounts_first() {
+        let client = client();
+        let instructions = client.claim(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            anchor_spl::token::ID,
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(instructions[0].program_id, anchor_spl::associated_token::ID);
+        assert_eq!(instructions[1].program_id, anchor_spl::associated_token::ID);
+        assert_eq!(instructions[2].program_id, client.program());
+    }
+}
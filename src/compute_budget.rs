@@ -0,0 +1,113 @@
+use crate::progress::Progress;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::compute_budget::ComputeBudgetInstruction;
+use solana_sdk::instruction::Instruction;
+use solana_sdk::pubkey::Pubkey;
+
+/// Max number of accounts `getRecentPrioritizationFees` accepts
+const MAX_PRIORITIZATION_FEE_ACCOUNTS: usize = 128;
+/// Percentile of the recent fees picked by the automatic priority fee
+const AUTO_PRIORITY_FEE_PERCENTILE: usize = 75;
+
+/// How the priority fee of transactions is set
+#[derive(Debug, Clone, Copy)]
+pub enum PriorityFee {
+    None,
+    /// A fixed price, in micro-lamports per compute unit
+    Fixed(u64),
+    /// A price picked from the fees recently paid to write the same accounts
+    Auto,
+}
+
+/// The compute budget instructions prepended to every transaction
+#[derive(Debug, Clone, Copy)]
+pub struct ComputeBudget {
+    pub unit_limit: Option<u32>,
+    pub priority_fee: PriorityFee,
+}
+
+impl ComputeBudget {
+    /// The compute budget instructions for a transaction made of `instructions`, reporting
+    /// the priority fee to `progress` when it is picked automatically
+    pub async fn instructions(
+        &self,
+        rpc_client: &RpcClient,
+        instructions: &[Instruction],
+        progress: &dyn Progress,
+    ) -> anyhow::Result<Vec<Instruction>> {
+        let mut budget = vec![];
+        if let Some(unit_limit) = self.unit_limit {
+            budget.push(ComputeBudgetInstruction::set_compute_unit_limit(unit_limit));
+        }
+        let price = match self.priority_fee {
+            PriorityFee::None => None,
+            PriorityFee::Fixed(price) => Some(price),
+            PriorityFee::Auto => {
+                let price = auto_priority_fee(rpc_client, &writable_accounts(instructions)).await?;
+                progress.status(&format!(
+                    "Priority fee: {} micro-lamports per compute unit",
+                    price
+                ));
+                Some(price)
+            }
+        };
+        if let Some(price) = price {
+            budget.push(ComputeBudgetInstruction::set_compute_unit_price(price));
+        }
+        Ok(budget)
+    }
+
+    /// Instructions of the same size as those of [`Self::instructions`], for sizing
+    /// transactions before the priority fee is picked
+    pub fn size_placeholder(&self) -> Vec<Instruction> {
+        let mut budget = vec![];
+        if self.unit_limit.is_some() {
+            budget.push(ComputeBudgetInstruction::set_compute_unit_limit(0));
+        }
+        if !matches!(self.priority_fee, PriorityFee::None) {
+            budget.push(ComputeBudgetInstruction::set_compute_unit_price(0));
+        }
+        budget
+    }
+
+    /// `instructions` preceded by their compute budget instructions
+    pub async fn with_budget(
+        &self,
+        rpc_client: &RpcClient,
+        instructions: &[Instruction],
+        progress: &dyn Progress,
+    ) -> anyhow::Result<Vec<Instruction>> {
+        let mut with_budget = self
+            .instructions(rpc_client, instructions, progress)
+            .await?;
+        with_budget.extend_from_slice(instructions);
+        Ok(with_budget)
+    }
+}
+
+/// A priority fee in the upper percentiles of those recently paid to write `accounts`
+async fn auto_priority_fee(rpc_client: &RpcClient, accounts: &[Pubkey]) -> anyhow::Result<u64> {
+    let accounts = &accounts[..accounts.len().min(MAX_PRIORITIZATION_FEE_ACCOUNTS)];
+    let mut fees = rpc_client
+        .get_recent_prioritization_fees(accounts)
+        .await?
+        .into_iter()
+        .map(|fee| fee.prioritization_fee)
+        .collect::<Vec<_>>();
+    if fees.is_empty() {
+        return Ok(0);
+    }
+    fees.sort_unstable();
+    Ok(fees[(fees.len() - 1) * AUTO_PRIORITY_FEE_PERCENTILE / 100])
+}
+
+/// The accounts the instructions write to, each once and in order of appearance
+pub(crate) fn writable_accounts(instructions: &[Instruction]) -> Vec<Pubkey> {
+    let mut writable = vec![];
+    for meta in instructions.iter().flat_map(|ix| ix.accounts.iter()) {
+        if meta.is_writable && !writable.contains(&meta.pubkey) {
+            writable.push(meta.pubkey);
+        }
+    }
+    writable
+}
@@ -0,0 +1,41 @@
+use anyhow::Context;
+use std::path::Path;
+
+/// Read the file at `path`, described as `kind` in errors, and parse it with `from_csv`
+/// when its extension is `.csv` and with `from_json` otherwise
+pub(crate) fn load<T>(
+    path: &Path,
+    kind: &str,
+    from_csv: impl FnOnce(&str) -> anyhow::Result<T>,
+    from_json: impl FnOnce(&str) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {} {}", kind, path.display()))?;
+    let is_csv = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    if is_csv {
+        from_csv(&contents)
+    } else {
+        from_json(&contents)
+    }
+}
+
+/// The non-empty lines of csv `contents` with their line number, split into trimmed fields.
+/// The first of them is left out when `is_header` matches it.
+pub(crate) fn rows(
+    contents: &str,
+    is_header: impl Fn(&[&str]) -> bool,
+) -> impl Iterator<Item = (usize, Vec<&str>)> {
+    let mut rows = contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(number, line)| (number, line.split(',').map(str::trim).collect::<Vec<_>>()))
+        .peekable();
+    if rows.peek().is_some_and(|(_, fields)| is_header(fields)) {
+        rows.next();
+    }
+    rows
+}
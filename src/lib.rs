@@ -0,0 +1,22 @@
+//! Client library of the GooseFX referral program: instruction builders and account
+//! fetching in [`ReferralClient`], plus the transaction sending, offline signing and lookup
+//! table helpers the `referral-rs` CLI is built on.
+//!
+//! The library never prints: what it has to report while sending transactions goes to the
+//! [`progress::Progress`] of the [`transaction::SendOptions`].
+
+pub mod client;
+pub mod compute_budget;
+mod csv;
+pub mod manifest;
+pub mod name;
+pub mod nonce;
+pub mod offline;
+pub mod price;
+pub mod progress;
+pub mod send;
+pub mod signer;
+pub mod transaction;
+pub mod utils;
+
+pub use client::ReferralClient;
@@ -1,43 +1,59 @@
-use anchor_lang::prelude::AccountMeta;
-use anchor_lang::AccountDeserialize;
+mod output;
+
+use crate::output::{status, OutputFormat, Printer};
+use anchor_spl::token::spl_token::amount_to_ui_amount_string_trimmed;
 use anyhow::Context;
 use clap::{Parser, Subcommand};
-use referral::accounts as referral_accounts;
-use referral::instruction as referral_instructions;
-use referral::InitializeReferralAccountParams;
-use referral::InitializeReferralAccountWithNameParams;
-use referral::REFERRAL_ATA_SEED;
-use referral::REFERRAL_SEED;
+use referral_client::client::{
+    ClaimAll, Claimable, ReferralAccountChange, ReferralClient, ReferralTokenAccountsCreation,
+};
+use referral_client::compute_budget::{ComputeBudget, PriorityFee};
+use referral_client::nonce::DurableNonce;
+use referral_client::offline::{self, TransactionEncoding};
+use referral_client::price::PriceFile;
+use referral_client::signer::{self, Role};
+use referral_client::transaction::{
+    send_legacy_transaction_with_signers, send_transaction, SendOptions,
+};
+use referral_client::{manifest, name, utils};
+use serde_json::json;
 use solana_client::nonblocking::rpc_client::RpcClient;
-use solana_client::rpc_config::RpcSendTransactionConfig;
-use solana_sdk::commitment_config::CommitmentConfig;
-use solana_sdk::instruction::Instruction;
-use solana_sdk::message::v0::Message;
+use solana_sdk::address_lookup_table::state::LookupTableStatus;
+use solana_sdk::hash::Hash;
 use solana_sdk::pubkey::Pubkey;
 use solana_sdk::signature::Keypair;
 use solana_sdk::signer::Signer;
-use solana_sdk::system_program;
-use solana_sdk::transaction::Transaction;
-use solana_sdk::transaction::VersionedTransaction;
+use solana_sdk::system_instruction;
 use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-
-mod utils;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 #[derive(Debug, Parser)]
 pub struct Opts {
-    /// The cluster RPC url
+    /// The cluster RPC url, defaults to the Solana CLI config json_rpc_url
     #[clap(long, env = "HTTP_URL")]
-    http_url: String,
+    http_url: Option<String>,
 
     /// The cluster WS url
     #[clap(long, env = "WS_URL")]
     ws_url: Option<String>,
 
-    /// Keypair base58 string
+    /// Keypair file path, `stdin`, `prompt:` or base58 string; defaults to the Solana CLI
+    /// config keypair_path
     #[clap(long, env)]
     keypair: Option<String>,
 
+    /// The Solana CLI config file, defaults to ~/.config/solana/cli/config.yml
+    #[clap(long, env = "SOLANA_CONFIG")]
+    config: Option<String>,
+
+    /// The payer funding account creations, a keypair source as for --keypair; defaults to
+    /// --keypair
+    #[clap(long, env)]
+    payer: Option<String>,
+
     /// The project account key
     #[clap(long, env)]
     project: Option<Pubkey>,
@@ -50,15 +66,107 @@ pub struct Opts {
     )]
     referral_program: Pubkey,
 
+    /// An existing lookup table to reuse for v0 transactions, extended with any missing addresses
+    #[clap(long, env)]
+    lookup_table: Option<Pubkey>,
+
+    /// File recording the lookup tables created by the CLI, so later runs can reuse them
+    #[clap(long, env, default_value = "lookup-tables.json")]
+    lookup_table_state: PathBuf,
+
+    /// Simulate every transaction instead of sending it
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Sign transactions with the available keypairs and print them instead of sending them,
+    /// for the missing signers to add their signatures with `sign` before `broadcast`
+    #[clap(long, alias = "export-tx", conflicts_with = "dry_run")]
+    sign_only: bool,
+
+    /// The blockhash to build transactions with instead of fetching the latest one, or the
+    /// nonce of --nonce when signing offline
+    #[clap(long, env)]
+    blockhash: Option<Hash>,
+
+    /// A durable nonce account whose nonce replaces the recent blockhash, advanced by every
+    /// transaction
+    #[clap(long, env)]
+    nonce: Option<Pubkey>,
+
+    /// The nonce authority, a keypair or only its pubkey with --sign-only; defaults to the
+    /// keypair
+    #[clap(long, env)]
+    nonce_authority: Option<String>,
+
+    /// Priority fee of every transaction, in micro-lamports per compute unit
+    #[clap(long, env, conflicts_with = "auto_priority_fee")]
+    priority_fee: Option<u64>,
+
+    /// Pick the priority fee from the fees recently paid to write the same accounts
+    #[clap(long)]
+    auto_priority_fee: bool,
+
+    /// Compute unit limit of every transaction
+    #[clap(long, env)]
+    compute_unit_limit: Option<u32>,
+
+    /// Encoding of the transactions printed by --sign-only and `sign`, and read by `sign`
+    /// and `broadcast`
+    #[clap(long, value_enum, default_value_t = Encoding::Base64)]
+    encoding: Encoding,
+
+    /// Output format
+    #[clap(long, value_enum, default_value_t = OutputFormat::Display)]
+    output: OutputFormat,
+
     /// Subcommand
     #[clap(subcommand)]
     command: Action,
 }
 
+/// The values of --encoding, one per [`TransactionEncoding`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    Base58,
+    Base64,
+}
+
+impl From<Encoding> for TransactionEncoding {
+    fn from(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Base58 => TransactionEncoding::Base58,
+            Encoding::Base64 => TransactionEncoding::Base64,
+        }
+    }
+}
+
 #[derive(Debug, Subcommand, Clone)]
 pub enum Action {
     /// Create a referral account, optionally wih a name
-    CreateReferralAccount { name: Option<String> },
+    CreateReferralAccount {
+        name: Option<String>,
+        /// Turn the name into a lowercase ASCII slug instead of rejecting whitespace
+        #[clap(long, requires = "name")]
+        slugify: bool,
+        /// The partner of the referral account, a pubkey, or a keypair source as for
+        /// --keypair when the partner has to sign; defaults to the keypair
+        #[clap(long)]
+        partner: Option<String>,
+    },
+    /// Create named referral accounts in bulk from a manifest of names, partners and shares
+    CreateReferralAccounts {
+        /// Path to a csv file of `name,partner,share_bps` rows with an optional header, or to a
+        /// json array of objects with the same fields; an empty share is the project default
+        #[clap(long)]
+        manifest: PathBuf,
+        /// Where to write the referral account and signature of each name, defaults to the
+        /// manifest path with a `.results.json` extension
+        #[clap(long)]
+        results: Option<PathBuf>,
+        /// Turn names into lowercase ASCII slugs instead of rejecting whitespace
+        #[clap(long)]
+        slugify: bool,
+    },
     /// Create token-accounts for a referral account
     CreateReferralTokenAccounts {
         /// The referral account key
@@ -67,215 +175,511 @@ pub enum Action {
         /// Path to a json file containing a list of mints
         path: String,
     },
+    /// Claim accumulated fees from the token-accounts of a referral account
+    Claim {
+        /// The referral account key
+        #[clap(long, env)]
+        referral_account: Pubkey,
+        /// A single mint to claim
+        #[clap(long, conflicts_with = "path")]
+        mint: Option<Pubkey>,
+        /// Path to a json file containing a list of mints
+        path: Option<String>,
+    },
+    /// Claim from every non-empty token-account of a referral account
+    ClaimAll {
+        /// The referral account key
+        #[clap(long, env)]
+        referral_account: Pubkey,
+    },
+    /// Update the share of a referral account, signed by the project admin
+    UpdateReferralAccount {
+        /// The referral account key
+        #[clap(long, env)]
+        referral_account: Pubkey,
+        /// The new share of the fees going to the partner, in basis points
+        #[clap(long)]
+        share_bps: u16,
+    },
+    /// Hand the partner role of a referral account to another key, signed by the current partner
+    TransferReferralAccount {
+        /// The referral account key
+        #[clap(long, env)]
+        referral_account: Pubkey,
+        /// The new partner
+        new_partner: Pubkey,
+        /// The current partner, a keypair or only its pubkey with --sign-only; defaults to
+        /// the keypair
+        #[clap(long)]
+        partner: Option<String>,
+    },
+    /// Value the unclaimed fees of a referral account in USD
+    Report {
+        /// The referral account key
+        #[clap(long, env)]
+        referral_account: Pubkey,
+        /// Path to a json or csv file of USD prices per mint
+        #[clap(long)]
+        prices: PathBuf,
+        /// Path to a json file containing the mints to report on, instead of scanning for
+        /// every referral token account
+        #[clap(long)]
+        mints: Option<String>,
+    },
+    /// List the referral accounts of a partner and/or a project
+    ListReferralAccounts {
+        /// Only list referral accounts of this partner
+        #[clap(long)]
+        partner: Option<Pubkey>,
+        /// Only list referral accounts of this project, defaults to --project
+        #[clap(long)]
+        project: Option<Pubkey>,
+    },
+    /// Fetch, deserialize, and display a project, with the number of its referral accounts
+    FetchProject {
+        /// The project to fetch, defaults to --project
+        account: Option<Pubkey>,
+    },
+    /// Derive the addresses of referral accounts and their token accounts, offline
+    Derive {
+        #[clap(subcommand)]
+        action: DeriveAction,
+    },
+    /// Manage durable nonce accounts
+    Nonce {
+        #[clap(subcommand)]
+        action: NonceAction,
+    },
+    /// Manage the address lookup tables owned by the keypair
+    Lut {
+        #[clap(subcommand)]
+        action: LutAction,
+    },
+    /// Add signatures to a transaction exported by --sign-only
+    Sign {
+        /// The encoded transaction, or a file holding it
+        transaction: String,
+        /// Additional keypairs to sign with, besides --keypair and --payer
+        #[clap(long = "signer")]
+        signers: Vec<String>,
+    },
+    /// Send a transaction exported by --sign-only once it carries every signature
+    Broadcast {
+        /// The encoded transaction, or a file holding it
+        transaction: String,
+    },
     /// Fetch, deserialize, and display a referral account
     FetchReferralAccount {
         /// The account to fetch
         account: Pubkey,
+        /// Also display the balances of the referral token accounts
+        #[clap(long)]
+        with_balances: bool,
+        /// Path to a json file containing the mints to display balances for, instead of
+        /// scanning for every referral token account
+        #[clap(long, requires = "with_balances")]
+        mints: Option<String>,
     },
 }
 
-/// Max number of addresses a LUT can contain
-const MAX_LUT_SIZE: usize = 256;
-/// How many accounts each init-token-account instruction needs
-const INIT_REFERRAL_ATA_ACCOUNTS_LEN: usize = 7;
-/// Max number of accounts that can fit in a legacy transaction
-const MAX_LEGACY_ACCOUNTS: usize = 32;
+#[derive(Debug, Subcommand, Clone)]
+pub enum DeriveAction {
+    /// Derive the address of the referral account of --project with a name
+    ReferralAccount {
+        name: String,
+        /// Turn the name into a lowercase ASCII slug, as create-referral-account --slugify
+        #[clap(long)]
+        slugify: bool,
+    },
+    /// Derive the address of the token account of a referral account for a mint. The
+    /// address is seeded by the referral account and the mint only, so it is the same
+    /// whether the mint belongs to the token or the token-2022 program.
+    ReferralTokenAccount {
+        /// The referral account key
+        #[clap(long, env)]
+        referral_account: Pubkey,
+        mint: Pubkey,
+    },
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum NonceAction {
+    /// Create a nonce account whose authority is --nonce-authority, or otherwise the payer
+    Create {
+        /// The keypair of the new nonce account, generated when not set
+        #[clap(long)]
+        nonce_keypair: Option<String>,
+        /// Lamports to fund the account with, defaults to its rent-exempt minimum
+        #[clap(long)]
+        lamports: Option<u64>,
+    },
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum LutAction {
+    /// List the lookup tables whose authority is the keypair
+    List,
+    /// Print the addresses stored in lookup tables
+    Show {
+        /// The lookup tables to show
+        #[clap(required = true)]
+        tables: Vec<Pubkey>,
+    },
+    /// Deactivate lookup tables, starting the cooldown before they can be closed
+    Deactivate {
+        /// The lookup tables to deactivate
+        #[clap(required = true)]
+        tables: Vec<Pubkey>,
+    },
+    /// Close deactivated lookup tables whose cooldown has passed, reclaiming their rent
+    Close {
+        /// The lookup tables to close
+        #[clap(required_unless_present = "all")]
+        tables: Vec<Pubkey>,
+        /// Close every closable lookup table whose authority is the keypair
+        #[clap(long, conflicts_with = "tables")]
+        all: bool,
+    },
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    dotenv::dotenv()?;
+    dotenv::dotenv().ok();
     let opts = Opts::parse();
-    let rpc_client = RpcClient::new(opts.http_url.clone());
-    let keypair = opts.keypair.map(|s| Keypair::from_base58_string(&s));
+    output::init(opts.output);
+    // Deriving addresses needs neither an RPC nor a keypair, so it runs before loading them
+    if let Action::Derive { action } = &opts.command {
+        return derive(opts.referral_program, opts.project, action.clone());
+    }
+    let cli_config = signer::load_cli_config(opts.config.as_deref())?;
+    // Blank HTTP_URL= or KEYPAIR= lines of a .env leave the Solana CLI config in charge
+    let http_url = opts
+        .http_url
+        .filter(|url| !url.trim().is_empty())
+        .unwrap_or(cli_config.json_rpc_url);
+    let rpc_client = Arc::new(RpcClient::new(http_url));
+    let client = ReferralClient::new(rpc_client.clone(), opts.referral_program);
+    let keypair = match opts.keypair.filter(|source| !source.trim().is_empty()) {
+        Some(source) => Some(signer::read_keypair(&source)?),
+        // Commands that do not sign run fine without the default keypair file
+        None if Path::new(&cli_config.keypair_path).is_file() => {
+            Some(signer::read_keypair(&cli_config.keypair_path)?)
+        }
+        None => None,
+    };
+    let payer = opts.payer.as_deref().map(Role::parse).transpose()?;
+    let nonce_authority = opts
+        .nonce_authority
+        .as_deref()
+        .map(Role::parse)
+        .transpose()?;
+    let nonce_authority_key = nonce_authority.as_ref().map(Role::pubkey);
+    let nonce = match opts.nonce {
+        Some(account) => Some(DurableNonce {
+            account,
+            authority: match nonce_authority {
+                Some(authority) => authority,
+                None => Role::Keypair(
+                    keypair
+                        .as_ref()
+                        .context("no nonce authority, set --nonce-authority or the keypair")?
+                        .insecure_clone(),
+                ),
+            },
+        }),
+        None => None,
+    };
+    let send_options = SendOptions {
+        lookup_table: opts.lookup_table,
+        lookup_table_state: opts.lookup_table_state,
+        dry_run: opts.dry_run,
+        sign_only: opts.sign_only,
+        blockhash: opts.blockhash,
+        nonce,
+        nonce_used: AtomicBool::default(),
+        compute_budget: ComputeBudget {
+            unit_limit: opts.compute_unit_limit,
+            priority_fee: match opts.priority_fee {
+                Some(price) => PriorityFee::Fixed(price),
+                None if opts.auto_priority_fee => PriorityFee::Auto,
+                None => PriorityFee::None,
+            },
+        },
+        encoding: opts.encoding.into(),
+        progress: Arc::new(Printer),
+    };
 
     match opts.command {
-        Action::CreateReferralAccount { name } => {
-            let keypair = keypair.context("keypair not set")?;
+        Action::CreateReferralAccount {
+            name,
+            slugify,
+            partner,
+        } => {
+            let name = name
+                .map(|name| name::validate(&name, slugify))
+                .transpose()?;
+            let payer = resolve_payer(&payer, &keypair)?;
+            let partner = partner.as_deref().map(Role::parse).transpose()?;
+            let partner_key = match (&partner, &keypair) {
+                (Some(partner), _) => partner.pubkey(),
+                (None, Some(keypair)) => keypair.pubkey(),
+                (None, None) => payer.pubkey(),
+            };
             let project = opts
                 .project
                 .context("no project specified for referral account creation")?;
             let referral_account = Keypair::new();
-            let (data, accounts) = if let Some(name) = name {
-                let referral_pda = Pubkey::find_program_address(
-                    &[REFERRAL_SEED, project.as_ref(), name.as_bytes()],
-                    &opts.referral_program,
-                )
-                .0;
-                let data = anchor_lang::InstructionData::data(
-                    &referral_instructions::InitializeReferralAccountWithName {
-                        params: InitializeReferralAccountWithNameParams { name },
-                    },
-                );
-
-                let accounts = anchor_lang::ToAccountMetas::to_account_metas(
-                    &referral_accounts::InitializeReferralAccountWithName {
-                        payer: keypair.pubkey(),
-                        partner: keypair.pubkey(),
-                        project,
-                        referral_account: referral_pda,
-                        system_program: solana_sdk::system_program::ID,
-                    },
-                    None,
-                );
-
-                (data, accounts)
-            } else {
-                let data = anchor_lang::InstructionData::data(
-                    &referral_instructions::InitializeReferralAccount {
-                        params: InitializeReferralAccountParams {},
-                    },
-                );
-
-                let accounts = anchor_lang::ToAccountMetas::to_account_metas(
-                    &referral_accounts::InitializeReferralAccount {
-                        payer: keypair.pubkey(),
-                        partner: keypair.pubkey(),
+            let (instruction, referral_account_key) = match &name {
+                Some(name) => client.create_referral_account_with_name(
+                    payer.pubkey(),
+                    partner_key,
+                    project,
+                    name,
+                )?,
+                None => (
+                    client.create_referral_account(
+                        payer.pubkey(),
+                        partner_key,
                         project,
-                        referral_account: referral_account.pubkey(),
-                        system_program: solana_sdk::system_program::ID,
-                    },
-                    None,
-                );
-
-                (data, accounts)
+                        referral_account.pubkey(),
+                    ),
+                    referral_account.pubkey(),
+                ),
             };
-            let instruction = Instruction::new_with_bytes(opts.referral_program, &data, accounts);
 
-            let recent_hash = rpc_client.get_latest_blockhash().await?;
-            let txn = Transaction::new_signed_with_payer(
+            let mut keypairs = vec![payer, &referral_account];
+            keypairs.extend(keypair.as_ref());
+            keypairs.extend(partner.as_ref().and_then(Role::keypair));
+            let signature = send_legacy_transaction_with_signers(
+                &rpc_client,
+                &payer.pubkey(),
+                &keypairs,
+                &send_options,
                 &[instruction],
-                Some(&keypair.pubkey()),
-                &vec![&keypair],
-                recent_hash,
-            );
-            let signature = rpc_client
-                .send_and_confirm_transaction_with_spinner_and_config(
-                    &txn,
-                    CommitmentConfig::confirmed(),
-                    RpcSendTransactionConfig {
-                        skip_preflight: true,
-                        preflight_commitment: Some(rpc_client.commitment().commitment),
-                        max_retries: Some(0),
-                        ..RpcSendTransactionConfig::default()
-                    },
+            )
+            .await?;
+            output::document(json!({
+                "referral_account": referral_account_key.to_string(),
+                "name": name,
+                "signature": signature.to_string(),
+                "dry_run": send_options.dry_run,
+            }))?;
+        }
+        Action::CreateReferralAccounts {
+            manifest,
+            results,
+            slugify,
+        } => {
+            let mut rows = manifest::load(&manifest)?;
+            for row in &mut rows {
+                row.name = name::validate(&row.name, slugify)?;
+            }
+            manifest::ensure_unique_names(&rows)?;
+            let results = results.unwrap_or_else(|| manifest.with_extension("results.json"));
+            let payer = resolve_payer(&payer, &keypair)?;
+            let project = opts
+                .project
+                .context("no project specified for referral account creation")?;
+            let outcome = client
+                .create_referral_accounts_from_manifest(
+                    payer,
+                    keypair.as_ref(),
+                    project,
+                    &rows,
+                    &send_options,
                 )
                 .await?;
-            println!("View confirmed txn at: https://solscan.io/tx/{}", signature);
+
+            if outcome.pending_shares > 0 {
+                status!("would set {} shares once created", outcome.pending_shares);
+            }
+            status!(
+                "created: {}, skipped: {}, failed: {}, failed shares: {}",
+                outcome.created.succeeded.len(),
+                outcome.skipped,
+                outcome.created.failed.len(),
+                outcome.failed_shares
+            );
+            if send_options.submits() {
+                manifest::save_results(&results, &outcome.results)?;
+                status!("results written to {}", results.display());
+            }
+            output::document(json!({
+                "project": project.to_string(),
+                "results": outcome.results,
+                "results_file": send_options.submits().then(|| results.display().to_string()),
+                "signatures": output::strings(
+                    &[&outcome.created.signatures[..], &outcome.updated.signatures[..]].concat()
+                ),
+                "lookup_tables": output::strings(&outcome.created.lookup_tables),
+                "dry_run": send_options.dry_run,
+            }))?;
+            outcome.ensure_succeeded()?;
         }
         Action::CreateReferralTokenAccounts {
             path,
             referral_account,
         } => {
-            let mints = serde_json::from_str::<Vec<String>>(&std::fs::read_to_string(path)?)?
-                .into_iter()
-                .filter_map(|p| Pubkey::from_str(&p).ok())
-                .collect::<HashSet<_>>()
-                .into_iter()
-                .collect::<Vec<_>>();
-            let keypair = keypair.context("keypair not set")?;
+            let mints = read_mints(&path)?;
+            let payer = resolve_payer(&payer, &keypair)?;
             let project = opts
                 .project
                 .context("no project specified for referral token-account creation")?;
-
-            let fits_legacy_transaction =
-                mints.len() < MAX_LEGACY_ACCOUNTS / INIT_REFERRAL_ATA_ACCOUNTS_LEN;
-
-            if fits_legacy_transaction {
-                let mut instructions = Vec::with_capacity(mints.len());
-                for mint in mints {
-                    let (data, accounts) = create_referral_token_account_data_and_accounts(
-                        keypair.pubkey(),
-                        opts.referral_program,
-                        mint,
-                        project,
-                        referral_account,
-                    );
-                    instructions.push(Instruction::new_with_bytes(
-                        opts.referral_program,
-                        &data,
-                        accounts,
-                    ));
-                }
-                let recent_hash = rpc_client.get_latest_blockhash().await?;
-                let txn = Transaction::new_signed_with_payer(
-                    &instructions,
-                    Some(&keypair.pubkey()),
-                    &vec![&keypair],
-                    recent_hash,
-                );
-                let signature = rpc_client
-                    .send_and_confirm_transaction_with_spinner_and_config(
-                        &txn,
-                        CommitmentConfig::confirmed(),
-                        RpcSendTransactionConfig {
-                            skip_preflight: true,
-                            preflight_commitment: Some(rpc_client.commitment().commitment),
-                            max_retries: Some(0),
-                            ..RpcSendTransactionConfig::default()
-                        },
-                    )
-                    .await?;
-                println!("View confirmed txn at: https://solscan.io/tx/{}", signature);
+            let ReferralTokenAccountsCreation {
+                missing,
+                existing,
+                invalid,
+                summary,
+            } = client
+                .create_missing_referral_token_accounts(
+                    payer,
+                    project,
+                    referral_account,
+                    &mints,
+                    &send_options,
+                )
+                .await?;
+            for (mint, reason) in &invalid {
+                status!("Skipping mint {}: {}", mint, reason);
+            }
+            let failed = summary.failed.len() + invalid.len();
+            status!(
+                "created: {}, skipped: {}, failed: {}",
+                summary.succeeded.len(),
+                existing.len(),
+                failed
+            );
+            output::document(json!({
+                "referral_account": referral_account.to_string(),
+                "created": summary
+                    .succeeded
+                    .iter()
+                    .map(|index| {
+                        let mint = missing[*index];
+                        json!({
+                            "mint": mint.to_string(),
+                            "referral_token_account": client
+                                .find_referral_token_account(referral_account, mint)
+                                .to_string(),
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+                "skipped": output::strings(&existing),
+                "failed": summary
+                    .failed
+                    .iter()
+                    .map(|index| missing[*index].to_string())
+                    .chain(invalid.iter().map(|(mint, _)| mint.to_string()))
+                    .collect::<Vec<_>>(),
+                "signatures": output::strings(&summary.signatures),
+                "lookup_tables": output::strings(&summary.lookup_tables),
+                "dry_run": send_options.dry_run,
+            }))?;
+            if failed > 0 {
+                anyhow::bail!("failed to create {} referral token accounts", failed);
+            }
+        }
+        Action::Claim {
+            referral_account,
+            mint,
+            path,
+        } => {
+            let mints = match (mint, path) {
+                (Some(mint), _) => vec![mint],
+                (None, Some(path)) => read_mints(&path)?,
+                (None, None) => anyhow::bail!("either --mint or a mints file is required"),
+            };
+            let keypair = keypair.context("keypair not set")?;
+            let summary = client
+                .claim_mints(&keypair, referral_account, &mints, &send_options)
+                .await?;
+            output::document(json!({
+                "referral_account": referral_account.to_string(),
+                "claimed": summary
+                    .succeeded
+                    .iter()
+                    .map(|index| json!({ "mint": mints[*index].to_string() }))
+                    .collect::<Vec<_>>(),
+                "failed": summary
+                    .failed
+                    .iter()
+                    .map(|index| mints[*index].to_string())
+                    .collect::<Vec<_>>(),
+                "signatures": output::strings(&summary.signatures),
+                "lookup_tables": output::strings(&summary.lookup_tables),
+                "dry_run": send_options.dry_run,
+            }))?;
+            summary.ensure_succeeded("claim")?;
+        }
+        Action::ClaimAll { referral_account } => {
+            let keypair = keypair.context("keypair not set")?;
+            let ClaimAll { claims, summary } = client
+                .claim_all(&keypair, referral_account, &send_options)
+                .await?;
+            let claim_json = |claim: &Claimable| {
+                json!({
+                    "mint": claim.mint.to_string(),
+                    "referral_token_account": claim.referral_token_account.to_string(),
+                    "amount": claim.amount.to_string(),
+                    "ui_amount": amount_to_ui_amount_string_trimmed(claim.amount, claim.decimals),
+                })
+            };
+            output::document(json!({
+                "referral_account": referral_account.to_string(),
+                "claimed": summary
+                    .succeeded
+                    .iter()
+                    .map(|index| claim_json(&claims[*index]))
+                    .collect::<Vec<_>>(),
+                "failed": summary
+                    .failed
+                    .iter()
+                    .map(|index| claims[*index].mint.to_string())
+                    .collect::<Vec<_>>(),
+                "signatures": output::strings(&summary.signatures),
+                "lookup_tables": output::strings(&summary.lookup_tables),
+                "dry_run": send_options.dry_run,
+            }))?;
+            summary.ensure_succeeded("claim")?;
+        }
+        Action::FetchReferralAccount {
+            account: address,
+            with_balances,
+            mints,
+        } => {
+            let account = client.fetch_referral_account(address).await?;
+            let balances = if with_balances {
+                let mints = mints.as_deref().map(read_mints).transpose()?;
+                Some(
+                    client
+                        .fetch_referral_token_balances(account.project, address, mints.as_deref())
+                        .await?,
+                )
             } else {
-                for mints in mints.chunks(MAX_LUT_SIZE / INIT_REFERRAL_ATA_ACCOUNTS_LEN) {
-                    // About 7 accounts per-instruction
-                    let mut instructions = Vec::with_capacity(mints.len());
-                    let mut extend_accounts = HashSet::new();
-
-                    for mint in mints {
-                        let (data, accounts) = create_referral_token_account_data_and_accounts(
-                            keypair.pubkey(),
-                            opts.referral_program,
-                            *mint,
-                            project,
-                            referral_account,
-                        );
-                        extend_accounts.extend(accounts.iter().map(|meta| meta.pubkey));
-                        instructions.push(Instruction::new_with_bytes(
-                            opts.referral_program,
-                            &data,
-                            accounts,
-                        ));
-                    }
-
-                    let lut = utils::create_and_extend_lookup_table(
-                        &keypair,
-                        &rpc_client,
-                        extend_accounts,
-                        None,
-                    )
-                    .await?;
-                    let lut_account = utils::fetch_address_lookup_table(&rpc_client, lut).await?;
-                    let blockhash = rpc_client.get_latest_blockhash().await?;
-                    let message = Message::try_compile(
-                        &keypair.pubkey(),
-                        &instructions,
-                        &[lut_account],
-                        blockhash,
-                    )?;
-                    let transaction = VersionedTransaction::try_new(
-                        solana_sdk::message::VersionedMessage::V0(message),
-                        &[&keypair],
-                    )?;
-                    let signature = rpc_client
-                        .send_and_confirm_transaction_with_spinner_and_config(
-                            &transaction,
-                            CommitmentConfig::confirmed(),
-                            RpcSendTransactionConfig {
-                                skip_preflight: true,
-                                preflight_commitment: Some(rpc_client.commitment().commitment),
-                                max_retries: Some(0),
-                                ..RpcSendTransactionConfig::default()
-                            },
-                        )
-                        .await?;
-                    println!("View confirmed txn at: https://solscan.io/tx/{}", signature);
+                None
+            };
+            if output::is_json() {
+                let mut document = referral_account_json(address, &account);
+                if let Some(balances) = &balances {
+                    document["balances"] = balances
+                        .iter()
+                        .map(|balance| {
+                            json!({
+                                "mint": balance.mint.to_string(),
+                                "referral_token_account": balance.address.to_string(),
+                                "decimals": balance.decimals,
+                                "exists": balance.amount.is_some(),
+                                "amount": balance.amount.unwrap_or_default().to_string(),
+                                "ui_amount": amount_to_ui_amount_string_trimmed(
+                                    balance.amount.unwrap_or_default(),
+                                    balance.decimals,
+                                ),
+                            })
+                        })
+                        .collect();
                 }
+                return output::document(document);
             }
-        }
-        Action::FetchReferralAccount { account } => {
-            let data = rpc_client.get_account_data(&account).await?;
-            let account = referral::ReferralAccount::try_deserialize(&mut &data[..])?;
 
             #[derive(Debug)]
             #[allow(dead_code)]
@@ -296,38 +700,479 @@ async fn main() -> anyhow::Result<()> {
                 }
             }
             println!("account: {:#?}", ReferralAccount::from(account));
+
+            if let Some(balances) = balances {
+                println!(
+                    "{:<44}  {:>8}  {:>24}  {:<6}",
+                    "mint", "decimals", "amount", "exists"
+                );
+                for balance in balances {
+                    println!(
+                        "{:<44}  {:>8}  {:>24}  {:<6}",
+                        balance.mint,
+                        balance.decimals,
+                        amount_to_ui_amount_string_trimmed(
+                            balance.amount.unwrap_or_default(),
+                            balance.decimals
+                        ),
+                        balance.amount.is_some()
+                    );
+                }
+            }
+        }
+        Action::UpdateReferralAccount {
+            referral_account,
+            share_bps,
+        } => {
+            let keypair = keypair.context("keypair not set")?;
+            let change = client
+                .update_share(&keypair, referral_account, share_bps, &send_options)
+                .await?;
+            print_referral_account_change(referral_account, &change, send_options.dry_run)?;
+        }
+        Action::TransferReferralAccount {
+            referral_account,
+            new_partner,
+            partner,
+        } => {
+            let payer = resolve_payer(&payer, &keypair)?;
+            let partner = match partner {
+                Some(partner) => Role::parse(&partner)?,
+                None => Role::Keypair(
+                    keypair
+                        .as_ref()
+                        .context("keypair not set")?
+                        .insecure_clone(),
+                ),
+            };
+            let change = client
+                .transfer(
+                    payer,
+                    &partner,
+                    referral_account,
+                    new_partner,
+                    &send_options,
+                )
+                .await?;
+            print_referral_account_change(referral_account, &change, send_options.dry_run)?;
+        }
+        Action::Report {
+            referral_account,
+            prices,
+            mints,
+        } => {
+            let mints = mints.as_deref().map(read_mints).transpose()?;
+            let price_provider = PriceFile::load(&prices)?;
+            let report = client
+                .report(referral_account, mints.as_deref(), &price_provider)
+                .await?;
+
+            if output::is_json() {
+                return output::document(json!({
+                    "referral_account": referral_account.to_string(),
+                    "mints": report
+                        .rows
+                        .iter()
+                        .map(|row| json!({
+                            "mint": row.mint.to_string(),
+                            "amount": row.amount.to_string(),
+                            "ui_amount": amount_to_ui_amount_string_trimmed(row.amount, row.decimals),
+                            "price_usd": row.price_usd,
+                            "value_usd": row.value_usd,
+                        }))
+                        .collect::<Vec<_>>(),
+                    "unpriced": output::strings(&report.unpriced()),
+                    "total_usd": report.total_usd,
+                }));
+            }
+
+            println!(
+                "{:<44}  {:>24}  {:>14}  {:>14}",
+                "mint", "amount", "price (usd)", "value (usd)"
+            );
+            for row in &report.rows {
+                println!(
+                    "{:<44}  {:>24}  {:>14}  {:>14}",
+                    row.mint,
+                    amount_to_ui_amount_string_trimmed(row.amount, row.decimals),
+                    row.price_usd
+                        .map_or("-".to_string(), |price| format!("{:.6}", price)),
+                    row.value_usd
+                        .map_or("-".to_string(), |value| format!("{:.2}", value)),
+                );
+            }
+            println!("total: ${:.2}", report.total_usd);
+        }
+        Action::ListReferralAccounts { partner, project } => {
+            let project = project.or(opts.project);
+            if partner.is_none() && project.is_none() {
+                anyhow::bail!("no partner or project specified to list referral accounts of");
+            }
+            let accounts = client.fetch_referral_accounts(partner, project).await?;
+            if output::is_json() {
+                return output::document(json!({
+                    "referral_accounts": accounts
+                        .iter()
+                        .map(|(address, account)| referral_account_json(*address, account))
+                        .collect::<Vec<_>>(),
+                }));
+            }
+
+            println!(
+                "{:<44}  {:<32}  {:>9}  {:<44}",
+                "address", "name", "share_bps", "project"
+            );
+            for (address, account) in accounts {
+                println!(
+                    "{:<44}  {:<32}  {:>9}  {:<44}",
+                    address,
+                    account.name.as_deref().unwrap_or("-"),
+                    account.share_bps,
+                    account.project
+                );
+            }
+        }
+        Action::FetchProject { account } => {
+            let address = account
+                .or(opts.project)
+                .context("no project specified to fetch")?;
+            let project = client.fetch_project(address).await?;
+            let referral_accounts = client.count_referral_accounts(None, Some(address)).await?;
+            if output::is_json() {
+                return output::document(json!({
+                    "address": address.to_string(),
+                    "admin": project.admin.to_string(),
+                    "name": project.name,
+                    "default_share_bps": project.default_share_bps,
+                    "referral_accounts": referral_accounts,
+                }));
+            }
+
+            #[derive(Debug)]
+            #[allow(dead_code)]
+            struct Project {
+                pub admin: Pubkey,
+                pub name: String,
+                pub default_share_bps: u16,
+                pub referral_accounts: usize,
+            }
+            println!(
+                "project: {:#?}",
+                Project {
+                    admin: project.admin,
+                    name: project.name,
+                    default_share_bps: project.default_share_bps,
+                    referral_accounts,
+                }
+            );
+        }
+        Action::Sign {
+            transaction,
+            signers,
+        } => {
+            let mut transaction = offline::decode_transaction(&transaction, send_options.encoding)?;
+            let signers = signers
+                .iter()
+                .map(|signer| signer::read_keypair(signer))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let mut keypairs = signers.iter().collect::<Vec<_>>();
+            keypairs.extend(keypair.as_ref());
+            keypairs.extend(payer.as_ref().and_then(Role::keypair));
+            let missing = offline::missing_signers(&transaction);
+            keypairs.retain(|keypair| missing.contains(&keypair.pubkey()));
+            if keypairs.is_empty() {
+                anyhow::bail!(
+                    "none of the keypairs is a missing signer of the transaction, missing: {}",
+                    output::strings(&missing).join(", ")
+                );
+            }
+            offline::sign_transaction(&mut transaction, &keypairs);
+            let missing = offline::missing_signers(&transaction);
+            output::export_transaction(
+                offline::encode_transaction(&transaction, send_options.encoding)?,
+                &missing,
+            );
+            output::document(json!({
+                "signed_by": keypairs.iter().map(|keypair| keypair.pubkey().to_string()).collect::<Vec<_>>(),
+            }))?;
         }
+        Action::Broadcast { transaction } => {
+            let transaction = offline::decode_transaction(&transaction, send_options.encoding)?;
+            let missing = offline::missing_signers(&transaction);
+            if !missing.is_empty() {
+                anyhow::bail!(
+                    "transaction is missing signatures from {}",
+                    output::strings(&missing).join(", ")
+                );
+            }
+            if transaction.verify_with_results().contains(&false) {
+                anyhow::bail!("transaction carries an invalid signature");
+            }
+            let signature =
+                send_transaction(&rpc_client, &send_options, &transaction, &[], &[]).await?;
+            output::document(json!({
+                "signature": signature.to_string(),
+                "dry_run": send_options.dry_run,
+            }))?;
+        }
+        Action::Derive { .. } => unreachable!("derive runs before loading signers"),
+        Action::Nonce { action } => match action {
+            NonceAction::Create {
+                nonce_keypair,
+                lamports,
+            } => {
+                let payer = resolve_payer(&payer, &keypair)?;
+                let nonce_account = match nonce_keypair {
+                    Some(source) => signer::read_keypair(&source)?,
+                    None => Keypair::new(),
+                };
+                let authority = nonce_authority_key.unwrap_or_else(|| payer.pubkey());
+                let lamports = match lamports {
+                    Some(lamports) => lamports,
+                    None => rpc_client
+                        .get_minimum_balance_for_rent_exemption(solana_sdk::nonce::State::size())
+                        .await?,
+                };
+                let instructions = system_instruction::create_nonce_account(
+                    &payer.pubkey(),
+                    &nonce_account.pubkey(),
+                    &authority,
+                    lamports,
+                );
+                let signature = send_legacy_transaction_with_signers(
+                    &rpc_client,
+                    &payer.pubkey(),
+                    &[payer, &nonce_account],
+                    &send_options,
+                    &instructions,
+                )
+                .await?;
+                status!("nonce account: {}", nonce_account.pubkey());
+                output::document(json!({
+                    "nonce_account": nonce_account.pubkey().to_string(),
+                    "authority": authority.to_string(),
+                    "lamports": lamports,
+                    "signature": signature.to_string(),
+                    "dry_run": send_options.dry_run,
+                }))?;
+            }
+        },
+        Action::Lut { action } => match action {
+            LutAction::List => {
+                let keypair = keypair.context("keypair not set")?;
+                let saved = utils::load_lookup_table_state(&send_options.lookup_table_state)?;
+                let tables =
+                    utils::fetch_lookup_tables_by_authority(&rpc_client, keypair.pubkey()).await?;
+                if output::is_json() {
+                    return output::document(json!({
+                        "lookup_tables": tables
+                            .iter()
+                            .map(|table| {
+                                let mut value = lookup_table_status_json(&table.status);
+                                value["address"] = json!(table.key.to_string());
+                                value["addresses"] = json!(table.addresses.len());
+                                value["saved"] = json!(saved.contains(&table.key));
+                                value
+                            })
+                            .collect::<Vec<_>>(),
+                    }));
+                }
+                for table in tables {
+                    println!(
+                        "{} addresses: {:>3} status: {:?}{}",
+                        table.key,
+                        table.addresses.len(),
+                        table.status,
+                        if saved.contains(&table.key) {
+                            " (saved)"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+            }
+            LutAction::Show { tables } => {
+                let tables = utils::fetch_lookup_table_infos(&rpc_client, &tables).await?;
+                if output::is_json() {
+                    return output::document(json!({
+                        "lookup_tables": tables
+                            .iter()
+                            .map(|table| json!({
+                                "address": table.key.to_string(),
+                                "addresses": output::strings(&table.addresses),
+                            }))
+                            .collect::<Vec<_>>(),
+                    }));
+                }
+                for table in tables {
+                    println!("{} ({} addresses):", table.key, table.addresses.len());
+                    for (index, address) in table.addresses.iter().enumerate() {
+                        println!("  {:>3}: {}", index, address);
+                    }
+                }
+            }
+            LutAction::Deactivate { tables } => {
+                let keypair = keypair.context("keypair not set")?;
+                let tables = utils::fetch_lookup_table_infos(&rpc_client, &tables).await?;
+                let changes =
+                    utils::deactivate_lookup_tables(&rpc_client, &keypair, &send_options, tables)
+                        .await?;
+                for table in &changes.skipped {
+                    status!("{} is already deactivated, skipping", table.key);
+                }
+                output::document(json!({
+                    "deactivated": output::strings(&changes.changed),
+                    "skipped": changes.skipped.iter().map(|table| table.key.to_string()).collect::<Vec<_>>(),
+                    "signatures": output::strings(&changes.signatures),
+                    "dry_run": send_options.dry_run,
+                }))?;
+            }
+            LutAction::Close { tables, all } => {
+                let keypair = keypair.context("keypair not set")?;
+                let tables = if all {
+                    utils::fetch_lookup_tables_by_authority(&rpc_client, keypair.pubkey()).await?
+                } else {
+                    utils::fetch_lookup_table_infos(&rpc_client, &tables).await?
+                };
+                let changes =
+                    utils::close_lookup_tables(&rpc_client, &keypair, &send_options, tables)
+                        .await?;
+                for table in &changes.skipped {
+                    match table.status {
+                        LookupTableStatus::Activated if !all => {
+                            status!("{} is still active, deactivate it first", table.key)
+                        }
+                        LookupTableStatus::Deactivating { remaining_blocks } => status!(
+                            "{} is cooling down, {} blocks remaining",
+                            table.key,
+                            remaining_blocks
+                        ),
+                        _ => {}
+                    }
+                }
+                output::document(json!({
+                    "closed": output::strings(&changes.changed),
+                    "skipped": changes.skipped.iter().map(|table| table.key.to_string()).collect::<Vec<_>>(),
+                    "signatures": output::strings(&changes.signatures),
+                    "dry_run": send_options.dry_run,
+                }))?;
+            }
+        },
     }
 
     Ok(())
 }
 
-fn create_referral_token_account_data_and_accounts(
-    payer: Pubkey,
-    program: Pubkey,
-    mint: Pubkey,
-    project: Pubkey,
-    referral_account: Pubkey,
-) -> (Vec<u8>, Vec<AccountMeta>) {
-    let referral_token_account = Pubkey::find_program_address(
-        &[REFERRAL_ATA_SEED, referral_account.as_ref(), mint.as_ref()],
-        &program,
-    )
-    .0;
-    let data =
-        anchor_lang::InstructionData::data(&referral_instructions::InitializeReferralTokenAccount);
-    let accounts = anchor_lang::ToAccountMetas::to_account_metas(
-        &referral_accounts::InitializeReferralTokenAccount {
-            payer,
-            project,
+fn derive(program: Pubkey, project: Option<Pubkey>, action: DeriveAction) -> anyhow::Result<()> {
+    match action {
+        DeriveAction::ReferralAccount { name, slugify } => {
+            let project = project.context("no project specified to derive from")?;
+            let name = name::validate(&name, slugify)?;
+            let (address, bump) = utils::find_referral_account_with_bump(program, project, &name);
+            if output::is_json() {
+                return output::document(json!({
+                    "address": address.to_string(),
+                    "bump": bump,
+                    "project": project.to_string(),
+                    "name": name,
+                }));
+            }
+            println!("address: {}", address);
+            println!("bump: {}", bump);
+            println!("name: {}", name);
+        }
+        DeriveAction::ReferralTokenAccount {
             referral_account,
-            referral_token_account,
             mint,
-            system_program: system_program::ID,
-            token_program: anchor_spl::token::ID, // todo: token-2022 support
-        },
-        None,
-    );
+        } => {
+            let (address, bump) =
+                utils::find_referral_token_account_with_bump(program, referral_account, mint);
+            if output::is_json() {
+                return output::document(json!({
+                    "address": address.to_string(),
+                    "bump": bump,
+                    "referral_account": referral_account.to_string(),
+                    "mint": mint.to_string(),
+                }));
+            }
+            println!("address: {}", address);
+            println!("bump: {}", bump);
+        }
+    }
+    Ok(())
+}
 
-    (data, accounts)
+fn referral_account_json(
+    address: Pubkey,
+    account: &referral::ReferralAccount,
+) -> serde_json::Value {
+    json!({
+        "address": address.to_string(),
+        "partner": account.partner.to_string(),
+        "project": account.project.to_string(),
+        "share_bps": account.share_bps,
+        "name": account.name,
+    })
+}
+
+/// Print a referral account before and after a change, with the fields that differ
+fn print_referral_account_change(
+    address: Pubkey,
+    change: &ReferralAccountChange,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    status!("{}:", address);
+    for diff in change.diff() {
+        if diff.changed() {
+            status!("  {}: {} -> {}", diff.field, diff.before, diff.after);
+        } else {
+            status!("  {}: {}", diff.field, diff.before);
+        }
+    }
+    output::document(json!({
+        "before": referral_account_json(address, &change.before),
+        "after": referral_account_json(address, &change.after),
+        "signature": change.signature.to_string(),
+        "dry_run": dry_run,
+    }))
+}
+
+fn lookup_table_status_json(status: &LookupTableStatus) -> serde_json::Value {
+    match status {
+        LookupTableStatus::Activated => json!({ "status": "active" }),
+        LookupTableStatus::Deactivating { remaining_blocks } => json!({
+            "status": "deactivating",
+            "remaining_blocks": remaining_blocks,
+        }),
+        LookupTableStatus::Deactivated => json!({ "status": "deactivated" }),
+    }
+}
+
+/// The keypair paying for transactions: --payer, or otherwise --keypair
+fn resolve_payer<'a>(
+    payer: &'a Option<Role>,
+    keypair: &'a Option<Keypair>,
+) -> anyhow::Result<&'a Keypair> {
+    match payer {
+        Some(Role::Keypair(payer)) => Ok(payer),
+        Some(Role::Pubkey(payer)) => {
+            anyhow::bail!(
+                "payer {} must sign, pass a keypair instead of a pubkey",
+                payer
+            )
+        }
+        None => keypair.as_ref().context("keypair not set"),
+    }
+}
+
+/// Read a json file containing a list of mints, dropping invalid and duplicate entries
+fn read_mints(path: &str) -> anyhow::Result<Vec<Pubkey>> {
+    Ok(
+        serde_json::from_str::<Vec<String>>(&std::fs::read_to_string(path)?)?
+            .into_iter()
+            .filter_map(|p| Pubkey::from_str(&p).ok())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>(),
+    )
 }
@@ -0,0 +1,168 @@
+use crate::csv;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A referral account to create, as listed in a manifest
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ManifestRow {
+    pub name: String,
+    #[serde(with = "pubkey_string")]
+    pub partner: Pubkey,
+    /// The share of the referral account, defaults to the project default share
+    #[serde(default)]
+    pub share_bps: Option<u16>,
+}
+
+/// Load a manifest, parsed as csv when its extension is `.csv` and as json otherwise.
+///
+/// A csv manifest has `name,partner,share_bps` rows, with an optional header and an empty
+/// or missing share for the project default. A json manifest is an array of objects with
+/// the same fields.
+pub fn load(path: &Path) -> anyhow::Result<Vec<ManifestRow>> {
+    csv::load(path, "manifest", from_csv, |contents| {
+        Ok(serde_json::from_str(contents)?)
+    })
+}
+
+/// Fail when a name is listed more than once, to be checked once names are normalized
+pub fn ensure_unique_names(rows: &[ManifestRow]) -> anyhow::Result<()> {
+    for (index, row) in rows.iter().enumerate() {
+        if rows[..index].iter().any(|other| other.name == row.name) {
+            anyhow::bail!("name {:?} is listed more than once", row.name);
+        }
+    }
+    Ok(())
+}
+
+pub fn from_csv(contents: &str) -> anyhow::Result<Vec<ManifestRow>> {
+    let mut rows = vec![];
+    for (line, fields) in csv::rows(contents, is_header) {
+        let (name, partner, share_bps) = match fields[..] {
+            [name, partner] => (name, partner, ""),
+            [name, partner, share_bps] => (name, partner, share_bps),
+            _ => anyhow::bail!("line {}: expected `name,partner,share_bps`", line),
+        };
+        let share_bps = match share_bps {
+            "" => Ok(None),
+            share_bps => u16::from_str(share_bps).map(Some),
+        };
+        match (Pubkey::from_str(partner), share_bps) {
+            (Ok(partner), Ok(share_bps)) => rows.push(ManifestRow {
+                name: name.to_string(),
+                partner,
+                share_bps,
+            }),
+            _ => anyhow::bail!("line {}: invalid partner or share", line),
+        }
+    }
+    Ok(rows)
+}
+
+/// Whether the fields are the `name,partner[,share_bps]` header row
+fn is_header(fields: &[&str]) -> bool {
+    let header = ["name", "partner", "share_bps"];
+    matches!(fields.len(), 2 | 3)
+        && fields
+            .iter()
+            .zip(header)
+            .all(|(field, column)| field.eq_ignore_ascii_case(column))
+}
+
+/// The outcome of a manifest row, as written to the results file
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestResult {
+    pub name: String,
+    pub partner: String,
+    pub referral_account: String,
+    /// `created`, `exists` or `failed`
+    pub status: &'static str,
+    pub signature: Option<String>,
+    pub share_bps: Option<u16>,
+    pub share_signature: Option<String>,
+}
+
+pub fn save_results(path: &Path, results: &[ManifestResult]) -> anyhow::Result<()> {
+    std::fs::write(path, serde_json::to_string_pretty(results)?)
+        .with_context(|| format!("failed to write results file {}", path.display()))
+}
+
+mod pubkey_string {
+    use serde::{Deserialize, Deserializer};
+    use solana_sdk::pubkey::Pubkey;
+    use std::str::FromStr;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pubkey, D::Error> {
+        let pubkey = String::deserialize(deserializer)?;
+        Pubkey::from_str(&pubkey).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARTNER: &str = "REFER4ZgmyYx9c6He5XfaTMiGfdLwRnkV4RPp9t9iF3";
+
+    fn row(name: &str, share_bps: Option<u16>) -> ManifestRow {
+        ManifestRow {
+            name: name.to_string(),
+            partner: Pubkey::from_str(PARTNER).unwrap(),
+            share_bps,
+        }
+    }
+
+    #[test]
+    fn parses_csv_rows() {
+        let csv = format!("alice,{PARTNER},500\n\n bob , {PARTNER} ,\ncarol,{PARTNER}\n");
+        assert_eq!(
+            from_csv(&csv).unwrap(),
+            vec![
+                row("alice", Some(500)),
+                row("bob", None),
+                row("carol", None)
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_the_csv_header() {
+        let csv = format!("name,partner,share_bps\nalice,{PARTNER},500\n");
+        assert_eq!(from_csv(&csv).unwrap(), vec![row("alice", Some(500))]);
+        let csv = format!("Name,Partner\nalice,{PARTNER}\n");
+        assert_eq!(from_csv(&csv).unwrap(), vec![row("alice", None)]);
+        let csv = format!("\n  \nname,partner\nalice,{PARTNER}\n");
+        assert_eq!(from_csv(&csv).unwrap(), vec![row("alice", None)]);
+    }
+
+    #[test]
+    fn rejects_an_invalid_first_row() {
+        assert!(from_csv(&format!("alice,{PARTNER},5OO\nbob,{PARTNER}\n")).is_err());
+        assert!(from_csv("alice,not-a-pubkey\n").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_rows() {
+        assert!(from_csv(&format!("alice,{PARTNER},70000\n")).is_err());
+        assert!(from_csv(&format!("alice\nbob,{PARTNER}\n")).is_err());
+        assert!(from_csv(&format!("alice,{PARTNER},1,2\n")).is_err());
+    }
+
+    #[test]
+    fn parses_json_rows() {
+        let json = format!(
+            r#"[{{"name": "alice", "partner": "{PARTNER}", "share_bps": 500}},
+                {{"name": "bob", "partner": "{PARTNER}"}}]"#
+        );
+        let rows = serde_json::from_str::<Vec<ManifestRow>>(&json).unwrap();
+        assert_eq!(rows, vec![row("alice", Some(500)), row("bob", None)]);
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        assert!(ensure_unique_names(&[row("alice", None), row("bob", None)]).is_ok());
+        assert!(ensure_unique_names(&[row("alice", None), row("alice", Some(1))]).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use solana_sdk::pubkey::MAX_SEED_LEN;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Max length of a referral account name in bytes. The name is a seed of the referral
+/// account PDA, after `REFERRAL_SEED` and the project, and `Pubkey::create_program_address`
+/// rejects seeds over `MAX_SEED_LEN` (solana-program `pubkey.rs`), so no longer name can
+/// have an account. The referral program sets no lower limit of its own, which the end to
+/// end tests check by creating an account whose name is this long.
+pub const MAX_NAME_LEN: usize = MAX_SEED_LEN;
+
+/// Check a referral account name can be used as a PDA seed and reproduced later, returning
+/// the name to derive the PDA from.
+///
+/// Names are normalized to Unicode NFC, so that visually identical names derive the same
+/// PDA. With `slugify`, the name is first turned into a lowercase ASCII slug instead.
+pub fn validate(name: &str, slugify: bool) -> anyhow::Result<String> {
+    let normalized = if slugify {
+        self::slugify(name)
+    } else {
+        name.nfc().collect::<String>()
+    };
+    if normalized.is_empty() {
+        anyhow::bail!("referral account name {:?} is empty", name);
+    }
+    if let Some(c) = normalized
+        .chars()
+        .find(|c| c.is_whitespace() || c.is_control())
+    {
+        anyhow::bail!(
+            "referral account name {:?} contains {:?}, slugify it to replace whitespace",
+            name,
+            c
+        );
+    }
+    if normalized.len() > MAX_NAME_LEN {
+        anyhow::bail!(
+            "referral account name {:?} is {} bytes long, names are limited to {} bytes",
+            name,
+            normalized.len(),
+            MAX_NAME_LEN
+        );
+    }
+    Ok(normalized)
+}
+
+/// Lowercase ASCII letters and digits, with every other run of characters replaced by a
+/// single `-`, and accents stripped rather than replaced
+fn slugify(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.nfkd().filter(|c| !is_combining_mark(*c)) {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_to_nfc() {
+        // `e` followed by a combining acute accent, and the precomposed `é`
+        let decomposed = "cafe\u{301}";
+        assert_eq!(validate(decomposed, false).unwrap(), "caf\u{e9}");
+        assert_eq!(
+            validate(decomposed, false).unwrap(),
+            validate("caf\u{e9}", false).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_empty_names() {
+        assert!(validate("", false).is_err());
+        assert!(validate("---", true).is_err());
+    }
+
+    #[test]
+    fn rejects_whitespace_and_control_characters() {
+        assert!(validate("my name", false).is_err());
+        assert!(validate("name\u{a0}", false).is_err());
+        assert!(validate("tab\tname", false).is_err());
+        assert!(validate("bell\u{7}", false).is_err());
+    }
+
+    #[test]
+    fn limits_names_in_bytes() {
+        assert_eq!(
+            validate(&"a".repeat(MAX_NAME_LEN), false).unwrap().len(),
+            32
+        );
+        assert!(validate(&"a".repeat(MAX_NAME_LEN + 1), false).is_err());
+        // 11 three-byte characters are 33 bytes
+        assert!(validate(&"\u{20ac}".repeat(11), false).is_err());
+        assert!(validate(&"\u{20ac}".repeat(10), false).is_ok());
+        // The limit applies to the normalized name: 16 decomposed `é` are 48 bytes, but 32
+        // once composed
+        assert_eq!(validate(&"e\u{301}".repeat(16), false).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn slugifies() {
+        assert_eq!(
+            validate("My Referral  Name!", true).unwrap(),
+            "my-referral-name"
+        );
+        assert_eq!(
+            validate("  Caf\u{e9} Cr\u{e8}me ", true).unwrap(),
+            "cafe-creme"
+        );
+        assert_eq!(validate("\u{fb01}ne", true).unwrap(), "fine");
+        assert_eq!(validate("tab\tname", true).unwrap(), "tab-name");
+    }
+
+    #[test]
+    fn slugifies_before_checking_the_length() {
+        let name = format!("{} x", "a".repeat(MAX_NAME_LEN - 2));
+        assert_eq!(validate(&name, true).unwrap().len(), MAX_NAME_LEN);
+        assert!(validate(&format!("{} xy", "a".repeat(MAX_NAME_LEN - 2)), true).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use crate::signer::Role;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::nonce_utils::nonblocking::{data_from_account, get_account};
+use solana_sdk::hash::Hash;
+use solana_sdk::instruction::Instruction;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::system_instruction::{self, SystemInstruction};
+use solana_sdk::system_program;
+use solana_sdk::transaction::VersionedTransaction;
+
+/// A durable nonce account whose nonce replaces the recent blockhash of transactions
+pub struct DurableNonce {
+    pub account: Pubkey,
+    pub authority: Role,
+}
+
+impl DurableNonce {
+    /// The nonce currently stored in the account
+    pub async fn blockhash(&self, rpc_client: &RpcClient) -> anyhow::Result<Hash> {
+        let account = get_account(rpc_client, &self.account).await?;
+        Ok(data_from_account(&account)?.blockhash())
+    }
+
+    /// The instruction advancing the nonce, which must come first in the transaction
+    pub fn advance_instruction(&self) -> Instruction {
+        system_instruction::advance_nonce_account(&self.account, &self.authority.pubkey())
+    }
+
+    /// `instructions` preceded by the nonce advance
+    pub fn with_advance(&self, instructions: &[Instruction]) -> Vec<Instruction> {
+        let mut with_advance = Vec::with_capacity(instructions.len() + 1);
+        with_advance.push(self.advance_instruction());
+        with_advance.extend_from_slice(instructions);
+        with_advance
+    }
+}
+
+/// The nonce account of a durable nonce transaction, which advances it in its first
+/// instruction
+pub fn durable_nonce_account(transaction: &VersionedTransaction) -> Option<Pubkey> {
+    let instruction = transaction.message.instructions().first()?;
+    let keys = transaction.message.static_account_keys();
+    if keys.get(instruction.program_id_index as usize) != Some(&system_program::ID) {
+        return None;
+    }
+    match bincode::deserialize(&instruction.data) {
+        Ok(SystemInstruction::AdvanceNonceAccount) => {
+            keys.get(*instruction.accounts.first()? as usize).copied()
+        }
+        _ => None,
+    }
+}
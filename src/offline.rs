@@ -0,0 +1,77 @@
+use base64::Engine;
+use solana_sdk::bs58;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{Keypair, Signature};
+use solana_sdk::signer::Signer;
+use solana_sdk::transaction::VersionedTransaction;
+use std::path::Path;
+
+/// How transactions signed offline are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionEncoding {
+    Base58,
+    Base64,
+}
+
+pub fn encode_transaction(
+    transaction: &VersionedTransaction,
+    encoding: TransactionEncoding,
+) -> anyhow::Result<String> {
+    let bytes = bincode::serialize(transaction)?;
+    Ok(match encoding {
+        TransactionEncoding::Base58 => bs58::encode(bytes).into_string(),
+        TransactionEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(bytes),
+    })
+}
+
+/// Decode a transaction given either encoded or as the path of a file holding it
+pub fn decode_transaction(
+    source: &str,
+    encoding: TransactionEncoding,
+) -> anyhow::Result<VersionedTransaction> {
+    let encoded = if Path::new(source).is_file() {
+        std::fs::read_to_string(source)?
+    } else {
+        source.to_string()
+    };
+    let bytes = match encoding {
+        TransactionEncoding::Base58 => bs58::decode(encoded.trim()).into_vec()?,
+        TransactionEncoding::Base64 => {
+            base64::engine::general_purpose::STANDARD.decode(encoded.trim())?
+        }
+    };
+    Ok(bincode::deserialize(&bytes)?)
+}
+
+/// Sign with every keypair that is a required signer of the transaction, returning how
+/// many signatures were added
+pub fn sign_transaction(transaction: &mut VersionedTransaction, keypairs: &[&Keypair]) -> usize {
+    let message = transaction.message.serialize();
+    let num_signers = transaction.message.header().num_required_signatures as usize;
+    let mut signed = 0;
+    for (index, key) in transaction
+        .message
+        .static_account_keys()
+        .iter()
+        .take(num_signers)
+        .enumerate()
+    {
+        if let Some(keypair) = keypairs.iter().find(|keypair| keypair.pubkey() == *key) {
+            transaction.signatures[index] = keypair.sign_message(&message);
+            signed += 1;
+        }
+    }
+    signed
+}
+
+/// The required signers of the transaction whose signature is still missing
+pub fn missing_signers(transaction: &VersionedTransaction) -> Vec<Pubkey> {
+    transaction
+        .message
+        .static_account_keys()
+        .iter()
+        .zip(&transaction.signatures)
+        .filter(|(_, signature)| **signature == Signature::default())
+        .map(|(key, _)| *key)
+        .collect()
+}
@@ -0,0 +1,194 @@
+use clap::ValueEnum;
+use referral_client::progress::Progress;
+use referral_client::transaction::{AccountChange, Simulation};
+use solana_sdk::pubkey::Pubkey;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// How commands print their results
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable progress and results
+    Display,
+    /// A single json document per command on stdout, with progress on stderr
+    Json,
+}
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Transactions exported for offline signing, added to the json document of the command
+static EXPORTED_TRANSACTIONS: Mutex<Vec<serde_json::Value>> = Mutex::new(vec![]);
+
+/// Transactions simulated in dry-run mode, added to the json document of the command
+static SIMULATIONS: Mutex<Vec<serde_json::Value>> = Mutex::new(vec![]);
+
+/// Select the output format for the rest of the process
+pub fn init(format: OutputFormat) {
+    JSON_OUTPUT.store(format == OutputFormat::Json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Print a progress or status line, to stdout for display output and to stderr for json
+/// output so that stdout only carries the json document.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::output::is_json() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use status;
+
+/// Prints what the library reports in the selected output format
+pub struct Printer;
+
+impl Progress for Printer {
+    fn status(&self, message: &str) {
+        status!("{}", message);
+    }
+
+    fn exported(&self, transaction: &str, missing_signers: &[Pubkey]) {
+        export_transaction(transaction.to_string(), missing_signers);
+    }
+
+    fn simulated(&self, simulation: &Simulation) {
+        if is_json() {
+            SIMULATIONS
+                .lock()
+                .unwrap()
+                .push(simulation_json(simulation));
+            return;
+        }
+        match &simulation.error {
+            Some(err) => status!("Simulated txn {}: failed: {}", simulation.signature, err),
+            None => status!("Simulated txn {}: ok", simulation.signature),
+        }
+        if let Some(units) = simulation.units_consumed {
+            status!("  compute units: {}", units);
+        }
+        status!("  logs:");
+        for log in &simulation.logs {
+            status!("    {}", log);
+        }
+        status!("  account changes:");
+        for change in &simulation.account_changes {
+            match change {
+                AccountChange::Created {
+                    address,
+                    lamports,
+                    size,
+                    owner,
+                } => status!(
+                    "    {}: created, {} lamports, {} bytes, owner {}",
+                    address,
+                    lamports,
+                    size,
+                    owner
+                ),
+                AccountChange::Closed { address, lamports } => {
+                    status!("    {}: closed, {} lamports returned", address, lamports)
+                }
+                AccountChange::Changed {
+                    address,
+                    lamports,
+                    size,
+                } => status!(
+                    "    {}: {} -> {} lamports, {} -> {} bytes",
+                    address,
+                    lamports.0,
+                    lamports.1,
+                    size.0,
+                    size.1
+                ),
+            }
+        }
+    }
+}
+
+fn simulation_json(simulation: &Simulation) -> serde_json::Value {
+    let account_changes = simulation
+        .account_changes
+        .iter()
+        .map(|change| match change {
+            AccountChange::Created {
+                address,
+                lamports,
+                size,
+                owner,
+            } => serde_json::json!({
+                "address": address.to_string(),
+                "change": "created",
+                "lamports": lamports,
+                "size": size,
+                "owner": owner.to_string(),
+            }),
+            AccountChange::Closed { address, lamports } => serde_json::json!({
+                "address": address.to_string(),
+                "change": "closed",
+                "lamports": lamports,
+            }),
+            AccountChange::Changed {
+                address,
+                lamports,
+                size,
+            } => serde_json::json!({
+                "address": address.to_string(),
+                "change": "changed",
+                "lamports": [lamports.0, lamports.1],
+                "size": [size.0, size.1],
+            }),
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "signature": simulation.signature.to_string(),
+        "error": simulation.error.as_ref().map(ToString::to_string),
+        "units_consumed": simulation.units_consumed,
+        "logs": simulation.logs,
+        "account_changes": account_changes,
+    })
+}
+
+/// Print a transaction exported for offline signing, or keep it for the json document
+pub fn export_transaction<T: ToString>(encoded: String, missing_signers: &[T]) {
+    if is_json() {
+        EXPORTED_TRANSACTIONS
+            .lock()
+            .unwrap()
+            .push(serde_json::json!({
+                "transaction": encoded,
+                "missing_signers": strings(missing_signers),
+            }));
+        return;
+    }
+    println!("transaction: {}", encoded);
+    if !missing_signers.is_empty() {
+        println!("  missing signers: {}", strings(missing_signers).join(", "));
+    }
+}
+
+/// Print the json document of a command, when json output is selected, with the
+/// transactions exported or simulated on the way
+pub fn document(mut value: serde_json::Value) -> anyhow::Result<()> {
+    if is_json() {
+        let exported = std::mem::take(&mut *EXPORTED_TRANSACTIONS.lock().unwrap());
+        if !exported.is_empty() {
+            value["transactions"] = serde_json::Value::Array(exported);
+        }
+        let simulations = std::mem::take(&mut *SIMULATIONS.lock().unwrap());
+        if !simulations.is_empty() {
+            value["simulations"] = serde_json::Value::Array(simulations);
+        }
+        println!("{}", serde_json::to_string_pretty(&value)?);
+    }
+    Ok(())
+}
+
+/// Render keys, signatures and the like as json strings
+pub fn strings<T: ToString>(items: &[T]) -> Vec<String> {
+    items.iter().map(ToString::to_string).collect()
+}
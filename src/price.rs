@@ -0,0 +1,122 @@
+use crate::csv;
+use anyhow::Context;
+use async_trait::async_trait;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A source of USD prices for mints
+#[async_trait]
+pub trait PriceProvider {
+    /// Fetch the USD price of each of `mints`, leaving out mints without a known price
+    async fn prices(&self, mints: &[Pubkey]) -> anyhow::Result<HashMap<Pubkey, f64>>;
+}
+
+/// Prices read from a local file, either a json object mapping mints to prices or a csv
+/// file with `mint,price` rows and an optional header
+#[derive(Debug, Clone, Default)]
+pub struct PriceFile {
+    prices: HashMap<Pubkey, f64>,
+}
+
+impl PriceFile {
+    /// Load a price file, parsed as csv when its extension is `.csv` and as json otherwise
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        csv::load(path, "price file", Self::from_csv, Self::from_json)
+    }
+
+    pub fn from_json(contents: &str) -> anyhow::Result<Self> {
+        let prices = serde_json::from_str::<HashMap<String, f64>>(contents)?
+            .into_iter()
+            .map(|(mint, price)| {
+                let mint =
+                    Pubkey::from_str(&mint).with_context(|| format!("invalid mint {}", mint))?;
+                Ok((mint, price))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { prices })
+    }
+
+    pub fn from_csv(contents: &str) -> anyhow::Result<Self> {
+        let is_header = |fields: &[&str]| {
+            matches!(fields, [mint, price]
+                if mint.eq_ignore_ascii_case("mint") && price.eq_ignore_ascii_case("price"))
+        };
+        let mut prices = HashMap::new();
+        for (line, fields) in csv::rows(contents, is_header) {
+            let [mint, price] = fields[..] else {
+                anyhow::bail!("line {}: expected `mint,price`", line);
+            };
+            match (Pubkey::from_str(mint), f64::from_str(price)) {
+                (Ok(mint), Ok(price)) => {
+                    prices.insert(mint, price);
+                }
+                _ => anyhow::bail!("line {}: invalid mint or price", line),
+            }
+        }
+        Ok(Self { prices })
+    }
+}
+
+#[async_trait]
+impl PriceProvider for PriceFile {
+    async fn prices(&self, mints: &[Pubkey]) -> anyhow::Result<HashMap<Pubkey, f64>> {
+        Ok(mints
+            .iter()
+            .filter_map(|mint| self.prices.get(mint).map(|price| (*mint, *price)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINT: &str = "So11111111111111111111111111111111111111112";
+
+    fn price(file: &PriceFile) -> Option<f64> {
+        file.prices.get(&Pubkey::from_str(MINT).unwrap()).copied()
+    }
+
+    #[test]
+    fn parses_json() {
+        let file = PriceFile::from_json(&format!(r#"{{"{MINT}": 142.5}}"#)).unwrap();
+        assert_eq!(price(&file), Some(142.5));
+        assert!(PriceFile::from_json(r#"{"not-a-mint": 1.0}"#).is_err());
+        assert!(PriceFile::from_json(&format!(r#"{{"{MINT}": "1.0"}}"#)).is_err());
+    }
+
+    #[test]
+    fn parses_csv() {
+        let file = PriceFile::from_csv(&format!("\n {MINT} , 142.5 \n")).unwrap();
+        assert_eq!(price(&file), Some(142.5));
+    }
+
+    #[test]
+    fn skips_the_csv_header() {
+        let file = PriceFile::from_csv(&format!("mint,price\n{MINT},1.5\n")).unwrap();
+        assert_eq!(price(&file), Some(1.5));
+        let file = PriceFile::from_csv(&format!("\n\nMint,Price\n{MINT},2.5\n")).unwrap();
+        assert_eq!(price(&file), Some(2.5));
+    }
+
+    #[test]
+    fn rejects_invalid_rows() {
+        assert!(PriceFile::from_csv(&format!("{MINT},abc\n")).is_err());
+        assert!(PriceFile::from_csv(&format!("{MINT}\n")).is_err());
+        assert!(PriceFile::from_csv("mint,price\nnot-a-mint,1.0\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn provides_known_prices_only() {
+        let file = PriceFile::from_csv(&format!("{MINT},2\n")).unwrap();
+        let unknown = Pubkey::new_unique();
+        let prices = file
+            .prices(&[Pubkey::from_str(MINT).unwrap(), unknown])
+            .await
+            .unwrap();
+        assert_eq!(prices.len(), 1);
+        assert!(!prices.contains_key(&unknown));
+    }
+}
@@ -0,0 +1,22 @@
+use crate::transaction::Simulation;
+use solana_sdk::pubkey::Pubkey;
+
+/// Receives what the library has to report while it sends transactions, since it never
+/// prints anything itself. Every report is dropped unless overridden.
+pub trait Progress: Send + Sync {
+    /// A line on what is being done, like a confirmed transaction or a picked priority fee
+    fn status(&self, _message: &str) {}
+
+    /// A transaction signed in sign-only mode instead of being sent, with the signers whose
+    /// signature is still missing
+    fn exported(&self, _transaction: &str, _missing_signers: &[Pubkey]) {}
+
+    /// The outcome of a transaction simulated in dry-run mode
+    fn simulated(&self, _simulation: &Simulation) {}
+}
+
+/// Drops every report
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Silent;
+
+impl Progress for Silent {}
@@ -0,0 +1,526 @@
+use async_trait::async_trait;
+use solana_client::client_error::{ClientError, ClientErrorKind};
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::nonce_utils::nonblocking::{data_from_account, get_account_with_commitment};
+use solana_client::rpc_config::RpcSendTransactionConfig;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::hash::Hash;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use solana_sdk::signer::SignerError;
+use solana_sdk::transaction::{TransactionError, VersionedTransaction};
+use std::time::Duration;
+
+/// How long to wait for a broadcast transaction before sending it again
+const REBROADCAST_INTERVAL: Duration = Duration::from_secs(2);
+/// How many times an expired transaction is re-signed with a fresh blockhash
+const MAX_RESIGNS: usize = 3;
+/// How many RPC requests in a row can fail before the RPC is deemed unreachable
+const MAX_RPC_FAILURES: usize = 5;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SendError {
+    #[error("transaction expired without landing after {attempts} attempts")]
+    Expired { attempts: usize },
+    #[error("transaction {signature} failed on chain: {error}")]
+    Failed {
+        signature: Signature,
+        error: TransactionError,
+    },
+    #[error("RPC unreachable: {0}")]
+    RpcUnreachable(Box<ClientError>),
+    #[error("RPC error: {0}")]
+    Rpc(Box<ClientError>),
+    #[error("failed to sign transaction: {0}")]
+    Signing(#[from] SignerError),
+}
+
+/// The RPC requests the send layer relies on, implemented by [`RpcClient`] and by mocks
+#[async_trait]
+pub trait SendRpc {
+    /// The latest blockhash and the last block height at which it is valid
+    async fn latest_blockhash(&self) -> Result<(Hash, u64), ClientError>;
+    async fn block_height(&self) -> Result<u64, ClientError>;
+    async fn send(&self, transaction: &VersionedTransaction) -> Result<Signature, ClientError>;
+    async fn blockhash_valid(&self, blockhash: &Hash) -> Result<bool, ClientError>;
+    /// The nonce currently stored in a durable nonce account
+    async fn nonce(&self, account: &Pubkey) -> Result<Hash, ClientError>;
+    /// The result of the transaction once confirmed, searching past the recent status cache
+    /// when `search_history` is set
+    async fn signature_status(
+        &self,
+        signature: &Signature,
+        search_history: bool,
+    ) -> Result<Option<Result<(), TransactionError>>, ClientError>;
+}
+
+#[async_trait]
+impl SendRpc for RpcClient {
+    async fn latest_blockhash(&self) -> Result<(Hash, u64), ClientError> {
+        self.get_latest_blockhash_with_commitment(CommitmentConfig::confirmed())
+            .await
+    }
+
+    async fn block_height(&self) -> Result<u64, ClientError> {
+        self.get_block_height_with_commitment(CommitmentConfig::confirmed())
+            .await
+    }
+
+    async fn send(&self, transaction: &VersionedTransaction) -> Result<Signature, ClientError> {
+        self.send_transaction_with_config(
+            transaction,
+            RpcSendTransactionConfig {
+                skip_preflight: true,
+                max_retries: Some(0),
+                ..RpcSendTransactionConfig::default()
+            },
+        )
+        .await
+    }
+
+    async fn blockhash_valid(&self, blockhash: &Hash) -> Result<bool, ClientError> {
+        self.is_blockhash_valid(blockhash, CommitmentConfig::confirmed())
+            .await
+    }
+
+    async fn nonce(&self, account: &Pubkey) -> Result<Hash, ClientError> {
+        let account = get_account_with_commitment(self, account, CommitmentConfig::confirmed())
+            .await
+            .map_err(|err| ClientErrorKind::Custom(err.to_string()))?;
+        Ok(data_from_account(&account)
+            .map_err(|err| ClientErrorKind::Custom(err.to_string()))?
+            .blockhash())
+    }
+
+    async fn signature_status(
+        &self,
+        signature: &Signature,
+        search_history: bool,
+    ) -> Result<Option<Result<(), TransactionError>>, ClientError> {
+        self.get_signature_status_with_commitment_and_history(
+            signature,
+            CommitmentConfig::confirmed(),
+            search_history,
+        )
+        .await
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SendConfig {
+    pub rebroadcast_interval: Duration,
+    pub max_resigns: usize,
+}
+
+impl Default for SendConfig {
+    fn default() -> Self {
+        SendConfig {
+            rebroadcast_interval: REBROADCAST_INTERVAL,
+            max_resigns: MAX_RESIGNS,
+        }
+    }
+}
+
+/// What keeps a transaction signed ahead of time valid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifetime {
+    /// Its recent blockhash, valid for about a minute
+    Blockhash,
+    /// The nonce of a durable nonce account, valid until the account is advanced
+    DurableNonce(Pubkey),
+}
+
+/// When a broadcast transaction can no longer land
+enum Expiry {
+    BlockHeight(u64),
+    Blockhash(Hash),
+    Nonce(Pubkey, Hash),
+}
+
+/// Send a transaction and wait for it to be confirmed.
+///
+/// `sign` builds the transaction for a blockhash. It is rebroadcast until it lands or its
+/// blockhash expires, and once expired and confirmed not to have landed, it is signed again
+/// with a fresh blockhash, up to `max_resigns` times. Every call to `sign` after the first
+/// is such a re-sign.
+pub async fn send_and_confirm<R, F>(
+    rpc: &R,
+    config: &SendConfig,
+    mut sign: F,
+) -> Result<Signature, SendError>
+where
+    R: SendRpc + Sync,
+    F: FnMut(Hash) -> Result<VersionedTransaction, SignerError>,
+{
+    let mut failures = Failures::default();
+    for _ in 0..=config.max_resigns {
+        let (blockhash, last_valid_block_height) =
+            failures.retry(|| rpc.latest_blockhash()).await?;
+        let transaction = sign(blockhash)?;
+        let expiry = Expiry::BlockHeight(last_valid_block_height);
+        if let Some(signature) = broadcast(rpc, config, &transaction, expiry, &mut failures).await?
+        {
+            return Ok(signature);
+        }
+    }
+    Err(SendError::Expired {
+        attempts: config.max_resigns + 1,
+    })
+}
+
+/// Send a transaction whose blockhash cannot be changed, because it was fixed by the
+/// caller, comes from a durable nonce or is already signed, and wait for it to be
+/// confirmed. It is rebroadcast until it lands or its `lifetime` ends.
+pub async fn send_and_confirm_signed<R>(
+    rpc: &R,
+    config: &SendConfig,
+    transaction: &VersionedTransaction,
+    lifetime: Lifetime,
+) -> Result<Signature, SendError>
+where
+    R: SendRpc + Sync,
+{
+    let blockhash = *transaction.message.recent_blockhash();
+    let expiry = match lifetime {
+        Lifetime::Blockhash => Expiry::Blockhash(blockhash),
+        Lifetime::DurableNonce(account) => Expiry::Nonce(account, blockhash),
+    };
+    broadcast(rpc, config, transaction, expiry, &mut Failures::default())
+        .await?
+        .ok_or(SendError::Expired { attempts: 1 })
+}
+
+/// Rebroadcast a transaction until it lands, returning its signature, or until it expires
+/// without landing, returning `None`
+async fn broadcast<R>(
+    rpc: &R,
+    config: &SendConfig,
+    transaction: &VersionedTransaction,
+    expiry: Expiry,
+    failures: &mut Failures,
+) -> Result<Option<Signature>, SendError>
+where
+    R: SendRpc + Sync,
+{
+    let signature = transaction.signatures[0];
+    // Sends are counted apart, so that other requests succeeding do not hide them failing
+    let mut send_failures = Failures::default();
+    loop {
+        // Dropped sends are covered by the next rebroadcast, rejected ones never land
+        if let Err(err) = rpc.send(transaction).await {
+            send_failures.record(err)?;
+        }
+        tokio::time::sleep(config.rebroadcast_interval).await;
+        let status = failures
+            .retry(|| rpc.signature_status(&signature, false))
+            .await?;
+        if let Some(result) = status {
+            return landed(signature, result).map(Some);
+        }
+        let expired = match expiry {
+            Expiry::BlockHeight(last_valid_block_height) => {
+                failures.retry(|| rpc.block_height()).await? > last_valid_block_height
+            }
+            Expiry::Blockhash(blockhash) => {
+                !failures.retry(|| rpc.blockhash_valid(&blockhash)).await?
+            }
+            Expiry::Nonce(account, nonce) => failures.retry(|| rpc.nonce(&account)).await? != nonce,
+        };
+        if expired {
+            break;
+        }
+    }
+
+    // The transaction may have landed right before expiring
+    let status = failures
+        .retry(|| rpc.signature_status(&signature, true))
+        .await?;
+    match status {
+        Some(result) => landed(signature, result).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn landed(
+    signature: Signature,
+    result: Result<(), TransactionError>,
+) -> Result<Signature, SendError> {
+    result
+        .map(|()| signature)
+        .map_err(|error| SendError::Failed { signature, error })
+}
+
+/// Counts the RPC requests failing in a row
+#[derive(Default)]
+struct Failures(usize);
+
+impl Failures {
+    /// Count a transport error towards the RPC being unreachable, while an error response
+    /// of the RPC, like a rejected transaction, is returned right away
+    fn record(&mut self, err: ClientError) -> Result<(), SendError> {
+        if !matches!(
+            err.kind(),
+            ClientErrorKind::Io(_) | ClientErrorKind::Reqwest(_)
+        ) {
+            return Err(SendError::Rpc(Box::new(err)));
+        }
+        self.0 += 1;
+        if self.0 >= MAX_RPC_FAILURES {
+            return Err(SendError::RpcUnreachable(Box::new(err)));
+        }
+        Ok(())
+    }
+
+    async fn retry<T, Fut>(&mut self, mut request: impl FnMut() -> Fut) -> Result<T, SendError>
+    where
+        Fut: std::future::Future<Output = Result<T, ClientError>>,
+    {
+        loop {
+            match request().await {
+                Ok(value) => {
+                    self.0 = 0;
+                    return Ok(value);
+                }
+                Err(err) => self.record(err)?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_client::rpc_request::{RpcError, RpcResponseErrorData};
+    use solana_sdk::instruction::InstructionError;
+    use solana_sdk::message::{Message, VersionedMessage};
+    use solana_sdk::signature::Keypair;
+    use solana_sdk::signer::Signer;
+    use solana_sdk::system_instruction;
+    use std::sync::Mutex;
+
+    /// An RPC whose block height advances by one on every request
+    #[derive(Default)]
+    struct MockRpc {
+        block_height: Mutex<u64>,
+        /// Blocks a blockhash stays valid for
+        blockhash_lifetime: u64,
+        /// Number of sends dropped before one lands
+        dropped_sends: Mutex<usize>,
+        /// Result of the transaction once it lands
+        result: Option<Result<(), TransactionError>>,
+        landed: Mutex<Option<Signature>>,
+        unreachable: bool,
+        /// Whether sends get an error response
+        rejected: bool,
+        /// The nonce of every durable nonce account
+        nonce: Hash,
+    }
+
+    impl MockRpc {
+        fn tick(&self) -> Result<u64, std::io::Error> {
+            if self.unreachable {
+                return Err(std::io::ErrorKind::ConnectionRefused.into());
+            }
+            let mut block_height = self.block_height.lock().unwrap();
+            *block_height += 1;
+            Ok(*block_height)
+        }
+    }
+
+    #[async_trait]
+    impl SendRpc for MockRpc {
+        async fn latest_blockhash(&self) -> Result<(Hash, u64), ClientError> {
+            let block_height = self.tick()?;
+            Ok((Hash::new_unique(), block_height + self.blockhash_lifetime))
+        }
+
+        async fn block_height(&self) -> Result<u64, ClientError> {
+            Ok(self.tick()?)
+        }
+
+        async fn send(&self, transaction: &VersionedTransaction) -> Result<Signature, ClientError> {
+            self.tick()?;
+            if self.rejected {
+                return Err(ClientErrorKind::RpcError(RpcError::RpcResponseError {
+                    code: -32602,
+                    message: "invalid transaction: Transaction failed to sanitize".to_string(),
+                    data: RpcResponseErrorData::Empty,
+                })
+                .into());
+            }
+            let mut dropped_sends = self.dropped_sends.lock().unwrap();
+            if *dropped_sends > 0 {
+                *dropped_sends -= 1;
+            } else if self.result.is_some() {
+                *self.landed.lock().unwrap() = Some(transaction.signatures[0]);
+            }
+            Ok(transaction.signatures[0])
+        }
+
+        async fn blockhash_valid(&self, _blockhash: &Hash) -> Result<bool, ClientError> {
+            Ok(self.tick()? <= self.blockhash_lifetime)
+        }
+
+        async fn nonce(&self, _account: &Pubkey) -> Result<Hash, ClientError> {
+            self.tick()?;
+            Ok(self.nonce)
+        }
+
+        async fn signature_status(
+            &self,
+            signature: &Signature,
+            _search_history: bool,
+        ) -> Result<Option<Result<(), TransactionError>>, ClientError> {
+            self.tick()?;
+            Ok(match *self.landed.lock().unwrap() {
+                Some(landed) if landed == *signature => self.result.clone(),
+                _ => None,
+            })
+        }
+    }
+
+    fn config() -> SendConfig {
+        SendConfig {
+            rebroadcast_interval: Duration::ZERO,
+            max_resigns: 2,
+        }
+    }
+
+    fn transfer(payer: &Keypair, blockhash: Hash) -> Result<VersionedTransaction, SignerError> {
+        let instruction = system_instruction::transfer(&payer.pubkey(), &Pubkey::new_unique(), 1);
+        let message =
+            Message::new_with_blockhash(&[instruction], Some(&payer.pubkey()), &blockhash);
+        VersionedTransaction::try_new(VersionedMessage::Legacy(message), &[payer])
+    }
+
+    #[tokio::test]
+    async fn lands_after_rebroadcasts() {
+        let payer = Keypair::new();
+        let rpc = MockRpc {
+            blockhash_lifetime: 100,
+            dropped_sends: Mutex::new(3),
+            result: Some(Ok(())),
+            ..MockRpc::default()
+        };
+        let mut signed = 0;
+        let signature = send_and_confirm(&rpc, &config(), |blockhash| {
+            signed += 1;
+            transfer(&payer, blockhash)
+        })
+        .await
+        .unwrap();
+        assert_eq!(Some(signature), *rpc.landed.lock().unwrap());
+        assert_eq!(signed, 1);
+    }
+
+    #[tokio::test]
+    async fn resigns_expired_transactions() {
+        let payer = Keypair::new();
+        let rpc = MockRpc {
+            blockhash_lifetime: 4,
+            dropped_sends: Mutex::new(2),
+            result: Some(Ok(())),
+            ..MockRpc::default()
+        };
+        let mut signed = 0;
+        send_and_confirm(&rpc, &config(), |blockhash| {
+            signed += 1;
+            transfer(&payer, blockhash)
+        })
+        .await
+        .unwrap();
+        assert_eq!(signed, 2);
+    }
+
+    #[tokio::test]
+    async fn reports_expired() {
+        let payer = Keypair::new();
+        let rpc = MockRpc {
+            blockhash_lifetime: 4,
+            ..MockRpc::default()
+        };
+        let err = send_and_confirm(&rpc, &config(), |blockhash| transfer(&payer, blockhash))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SendError::Expired { attempts: 3 }));
+    }
+
+    #[tokio::test]
+    async fn reports_program_errors() {
+        let payer = Keypair::new();
+        let error = TransactionError::InstructionError(0, InstructionError::Custom(6000));
+        let rpc = MockRpc {
+            blockhash_lifetime: 100,
+            result: Some(Err(error.clone())),
+            ..MockRpc::default()
+        };
+        let err = send_and_confirm(&rpc, &config(), |blockhash| transfer(&payer, blockhash))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SendError::Failed { error: failed, .. } if failed == error));
+    }
+
+    #[tokio::test]
+    async fn reports_unreachable_rpc() {
+        let payer = Keypair::new();
+        let rpc = MockRpc {
+            unreachable: true,
+            ..MockRpc::default()
+        };
+        let err = send_and_confirm(&rpc, &config(), |blockhash| transfer(&payer, blockhash))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SendError::RpcUnreachable(_)));
+    }
+
+    #[tokio::test]
+    async fn reports_rejected_transactions() {
+        let payer = Keypair::new();
+        let rpc = MockRpc {
+            blockhash_lifetime: 100,
+            rejected: true,
+            ..MockRpc::default()
+        };
+        let mut signed = 0;
+        let err = send_and_confirm(&rpc, &config(), |blockhash| {
+            signed += 1;
+            transfer(&payer, blockhash)
+        })
+        .await
+        .unwrap_err();
+        assert!(matches!(err, SendError::Rpc(_)));
+        assert_eq!(signed, 1);
+    }
+
+    #[tokio::test]
+    async fn signed_transactions_expire_with_their_blockhash() {
+        let payer = Keypair::new();
+        let rpc = MockRpc {
+            blockhash_lifetime: 4,
+            ..MockRpc::default()
+        };
+        let transaction = transfer(&payer, Hash::new_unique()).unwrap();
+        let err = send_and_confirm_signed(&rpc, &config(), &transaction, Lifetime::Blockhash)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SendError::Expired { attempts: 1 }));
+    }
+
+    #[tokio::test]
+    async fn durable_nonce_transactions_outlive_blockhashes() {
+        let payer = Keypair::new();
+        let rpc = MockRpc {
+            blockhash_lifetime: 4,
+            dropped_sends: Mutex::new(10),
+            result: Some(Ok(())),
+            nonce: Hash::new_unique(),
+            ..MockRpc::default()
+        };
+        let transaction = transfer(&payer, rpc.nonce).unwrap();
+        let lifetime = Lifetime::DurableNonce(Pubkey::new_unique());
+        let signature = send_and_confirm_signed(&rpc, &config(), &transaction, lifetime)
+            .await
+            .unwrap();
+        assert_eq!(signature, transaction.signatures[0]);
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::Context;
+use solana_cli_config::Config;
+use solana_sdk::bs58;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Keypair;
+use solana_sdk::signer::keypair;
+use solana_sdk::signer::Signer;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A transaction role, held either by a local keypair or, when the role does not need to
+/// sign, only known by its pubkey
+pub enum Role {
+    Keypair(Keypair),
+    Pubkey(Pubkey),
+}
+
+impl Role {
+    /// Parse a bare pubkey, or otherwise a keypair source as read by [`read_keypair`]
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        match Pubkey::from_str(source) {
+            Ok(pubkey) => Ok(Role::Pubkey(pubkey)),
+            Err(_) => read_keypair(source).map(Role::Keypair),
+        }
+    }
+
+    pub fn pubkey(&self) -> Pubkey {
+        match self {
+            Role::Keypair(keypair) => keypair.pubkey(),
+            Role::Pubkey(pubkey) => *pubkey,
+        }
+    }
+
+    pub fn keypair(&self) -> Option<&Keypair> {
+        match self {
+            Role::Keypair(keypair) => Some(keypair),
+            Role::Pubkey(_) => None,
+        }
+    }
+}
+
+/// Read a keypair from a source following the Solana CLI conventions: `stdin` for a json
+/// keypair on standard input, `prompt:` for a seed phrase, a json keypair file path, or a
+/// base58 secret string
+pub fn read_keypair(source: &str) -> anyhow::Result<Keypair> {
+    let source = source.trim();
+    if source == "stdin" {
+        return keypair::read_keypair(&mut std::io::stdin())
+            .map_err(|e| anyhow::anyhow!("failed to read keypair from stdin: {}", e));
+    }
+    if source.starts_with("prompt:") || source == "ASK" {
+        let seed_phrase = rpassword::prompt_password("Seed phrase: ")?;
+        let passphrase = rpassword::prompt_password("Passphrase (empty for none): ")?;
+        return keypair::keypair_from_seed_phrase_and_passphrase(seed_phrase.trim(), &passphrase)
+            .map_err(|e| anyhow::anyhow!("invalid seed phrase: {}", e));
+    }
+    let path = Path::new(source.strip_prefix("file:").unwrap_or(source));
+    if path.is_file() {
+        return keypair::read_keypair_file(path)
+            .map_err(|e| anyhow::anyhow!("failed to read keypair file {}: {}", path.display(), e));
+    }
+    let bytes = bs58::decode(source).into_vec().with_context(|| {
+        format!(
+            "keypair {} is neither a keypair file, stdin, prompt: nor a base58 string",
+            source
+        )
+    })?;
+    Keypair::from_bytes(&bytes).context("keypair is not a valid 64-byte secret key")
+}
+
+/// The Solana CLI config, or its defaults when the file does not exist
+pub fn load_cli_config(path: Option<&str>) -> anyhow::Result<Config> {
+    match path.or(solana_cli_config::CONFIG_FILE.as_deref()) {
+        Some(path) if Path::new(path).exists() => Config::load(path)
+            .map_err(|e| anyhow::anyhow!("failed to load Solana CLI config {}: {}", path, e)),
+        _ => Ok(Config::default()),
+    }
+}
@@ -0,0 +1,680 @@
+use crate::compute_budget::{self, ComputeBudget};
+use crate::nonce::{self, DurableNonce};
+use crate::offline::{self, TransactionEncoding};
+use crate::progress::Progress;
+use crate::send::{self, Lifetime, SendConfig};
+use crate::utils;
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::rpc_client::SerializableTransaction;
+use solana_client::rpc_config::RpcSimulateTransactionAccountsConfig;
+use solana_client::rpc_config::RpcSimulateTransactionConfig;
+use solana_sdk::account::Account;
+use solana_sdk::address_lookup_table::instruction::derive_lookup_table_address;
+use solana_sdk::address_lookup_table::AddressLookupTableAccount;
+use solana_sdk::hash::Hash;
+use solana_sdk::instruction::Instruction;
+use solana_sdk::message::v0::Message;
+use solana_sdk::message::VersionedMessage;
+use solana_sdk::packet::PACKET_DATA_SIZE;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Keypair;
+use solana_sdk::signature::Signature;
+use solana_sdk::signer::Signer;
+use solana_sdk::transaction::{TransactionError, VersionedTransaction};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Max number of accounts that can fit in a legacy transaction
+const MAX_LEGACY_ACCOUNTS: usize = 32;
+
+/// Options shared by every transaction-sending path
+pub struct SendOptions {
+    /// An existing lookup table to reuse for v0 transactions
+    pub lookup_table: Option<Pubkey>,
+    /// File recording the lookup tables created by the CLI
+    pub lookup_table_state: PathBuf,
+    /// Simulate transactions instead of sending them
+    pub dry_run: bool,
+    /// Print signed transactions instead of sending them
+    pub sign_only: bool,
+    /// Blockhash to build transactions with, instead of the latest one
+    pub blockhash: Option<Hash>,
+    /// Durable nonce replacing the recent blockhash
+    pub nonce: Option<DurableNonce>,
+    /// Set once a transaction is signed with the durable nonce in sign-only mode, since the
+    /// nonce only advances when that transaction lands and a second one would reuse it
+    pub nonce_used: AtomicBool,
+    pub compute_budget: ComputeBudget,
+    pub encoding: TransactionEncoding,
+    /// Receives sent, simulated and exported transactions
+    pub progress: Arc<dyn Progress>,
+}
+
+impl SendOptions {
+    /// Whether transactions actually land, rather than being simulated or exported
+    pub fn submits(&self) -> bool {
+        !self.dry_run && !self.sign_only
+    }
+
+    /// `instructions`, preceded by the compute budget instructions and by the nonce advance
+    /// when a durable nonce is used
+    pub async fn prepare_instructions(
+        &self,
+        rpc_client: &RpcClient,
+        instructions: &[Instruction],
+    ) -> anyhow::Result<Vec<Instruction>> {
+        let instructions = self
+            .compute_budget
+            .with_budget(rpc_client, instructions, &*self.progress)
+            .await?;
+        Ok(match &self.nonce {
+            Some(nonce) => nonce.with_advance(&instructions),
+            None => instructions,
+        })
+    }
+
+    /// Instructions of the same size as those [`Self::prepare_instructions`] adds
+    fn size_placeholder(&self) -> Vec<Instruction> {
+        let mut placeholder = self.compute_budget.size_placeholder();
+        placeholder.extend(self.nonce.as_ref().map(DurableNonce::advance_instruction));
+        placeholder
+    }
+}
+
+/// Which instruction groups landed, and the transactions and lookup tables that carried them
+#[derive(Debug, Default)]
+pub struct SendSummary {
+    /// Indices of the groups that landed
+    pub succeeded: Vec<usize>,
+    /// Indices of the groups that were part of a failed transaction
+    pub failed: Vec<usize>,
+    pub signatures: Vec<Signature>,
+    /// The transaction that carried each group that landed
+    pub signature_by_group: HashMap<usize, Signature>,
+    pub lookup_tables: Vec<Pubkey>,
+}
+
+impl SendSummary {
+    pub fn ensure_succeeded(&self, action: &str) -> anyhow::Result<()> {
+        if !self.failed.is_empty() {
+            anyhow::bail!(
+                "failed to {} {} of {} mints",
+                action,
+                self.failed.len(),
+                self.succeeded.len() + self.failed.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Record the outcome of the transaction carrying the groups at `indices`
+    fn record(
+        &mut self,
+        indices: Range<usize>,
+        result: anyhow::Result<(Signature, Option<Pubkey>)>,
+        progress: &dyn Progress,
+    ) {
+        match result {
+            Ok((signature, lookup_table)) => {
+                self.signature_by_group
+                    .extend(indices.clone().map(|index| (index, signature)));
+                self.succeeded.extend(indices);
+                self.signatures.push(signature);
+                if let Some(lookup_table) = lookup_table {
+                    if !self.lookup_tables.contains(&lookup_table) {
+                        self.lookup_tables.push(lookup_table);
+                    }
+                }
+            }
+            Err(err) => {
+                progress.status(&format!(
+                    "Transaction for {} items failed: {:#}",
+                    indices.len(),
+                    err
+                ));
+                self.failed.extend(indices);
+            }
+        }
+    }
+}
+
+/// Send one group of instructions per item, packing every group into a single legacy
+/// transaction when they fit, and into LUT-backed v0 transactions of at most
+/// `max_groups_per_transaction` groups otherwise. A failed transaction is reported and
+/// counted, and the remaining transactions are still sent.
+pub async fn send_instruction_groups(
+    rpc_client: &RpcClient,
+    keypair: &Keypair,
+    send_options: &SendOptions,
+    groups: Vec<Vec<Instruction>>,
+    accounts_per_group: usize,
+    max_groups_per_transaction: usize,
+) -> SendSummary {
+    let mut summary = SendSummary::default();
+    if groups.is_empty() {
+        return summary;
+    }
+
+    let fits_legacy_transaction = groups.len() < MAX_LEGACY_ACCOUNTS / accounts_per_group;
+    let chunk_size = if fits_legacy_transaction {
+        groups.len()
+    } else {
+        std::cmp::min(
+            utils::MAX_LUT_SIZE / accounts_per_group,
+            max_groups_per_transaction,
+        )
+    };
+
+    for (chunk_index, groups) in groups.chunks(chunk_size).enumerate() {
+        let indices = chunk_index * chunk_size..chunk_index * chunk_size + groups.len();
+        let instructions = groups.concat();
+        let result = if fits_legacy_transaction {
+            send_legacy_transaction(rpc_client, keypair, send_options, &instructions)
+                .await
+                .map(|signature| (signature, None))
+        } else {
+            send_lookup_table_transaction(rpc_client, keypair, send_options, &instructions)
+                .await
+                .map(|(signature, lookup_table)| (signature, Some(lookup_table)))
+        };
+        summary.record(indices, result, &*send_options.progress);
+    }
+
+    summary
+}
+
+/// Send one group of instructions per item in legacy transactions, packing as many groups
+/// as fit in a transaction, up to `max_groups_per_transaction`. Meant for groups whose
+/// addresses are used once, which are not worth the rent of a lookup table, so only a group
+/// too large for a legacy transaction on its own goes in a lookup table transaction. A
+/// failed transaction is reported and counted, and the remaining transactions are still
+/// sent.
+pub async fn send_legacy_instruction_groups(
+    rpc_client: &RpcClient,
+    keypair: &Keypair,
+    send_options: &SendOptions,
+    groups: Vec<Vec<Instruction>>,
+    max_groups_per_transaction: usize,
+) -> SendSummary {
+    let mut summary = SendSummary::default();
+    let placeholder = send_options.size_placeholder();
+    let fits = |groups: &[Vec<Instruction>]| {
+        let instructions = [placeholder.clone(), groups.concat()].concat();
+        legacy_transaction_size(&keypair.pubkey(), &instructions) <= PACKET_DATA_SIZE
+    };
+
+    let mut start = 0;
+    while start < groups.len() {
+        if !fits(&groups[start..=start]) {
+            let result =
+                send_lookup_table_transaction(rpc_client, keypair, send_options, &groups[start])
+                    .await
+                    .map(|(signature, lookup_table)| (signature, Some(lookup_table)));
+            summary.record(start..start + 1, result, &*send_options.progress);
+            start += 1;
+            continue;
+        }
+        let mut end = start + 1;
+        while end < groups.len()
+            && end - start < max_groups_per_transaction
+            && fits(&groups[start..=end])
+        {
+            end += 1;
+        }
+        let result = send_legacy_transaction(
+            rpc_client,
+            keypair,
+            send_options,
+            &groups[start..end].concat(),
+        )
+        .await
+        .map(|signature| (signature, None));
+        summary.record(start..end, result, &*send_options.progress);
+        start = end;
+    }
+
+    summary
+}
+
+/// The serialized size of a legacy transaction made of `instructions`
+fn legacy_transaction_size(payer: &Pubkey, instructions: &[Instruction]) -> usize {
+    let message = solana_sdk::message::Message::new(instructions, Some(payer));
+    // The signatures are prefixed by their count, a single byte for so few of them
+    1 + message.header.num_required_signatures as usize * std::mem::size_of::<Signature>()
+        + message.serialize().len()
+}
+
+pub async fn send_legacy_transaction(
+    rpc_client: &RpcClient,
+    keypair: &Keypair,
+    send_options: &SendOptions,
+    instructions: &[Instruction],
+) -> anyhow::Result<Signature> {
+    send_legacy_transaction_with_signers(
+        rpc_client,
+        &keypair.pubkey(),
+        &[keypair],
+        send_options,
+        instructions,
+    )
+    .await
+}
+
+/// Send a legacy transaction paid by `payer`, signed by those of `keypairs` it requires
+pub async fn send_legacy_transaction_with_signers(
+    rpc_client: &RpcClient,
+    payer: &Pubkey,
+    keypairs: &[&Keypair],
+    send_options: &SendOptions,
+    instructions: &[Instruction],
+) -> anyhow::Result<Signature> {
+    let instructions = &send_options
+        .prepare_instructions(rpc_client, instructions)
+        .await?;
+    let recent_hash = recent_blockhash(rpc_client, send_options).await?;
+    let message =
+        solana_sdk::message::Message::new_with_blockhash(instructions, Some(payer), &recent_hash);
+    sign_and_send_transaction(
+        rpc_client,
+        send_options,
+        VersionedMessage::Legacy(message),
+        keypairs,
+        instructions,
+    )
+    .await
+}
+
+/// The blockhash to build a transaction with: the fixed one, the durable nonce, or
+/// otherwise the latest one
+async fn recent_blockhash(
+    rpc_client: &RpcClient,
+    send_options: &SendOptions,
+) -> anyhow::Result<Hash> {
+    if let Some(blockhash) = send_options.blockhash {
+        return Ok(blockhash);
+    }
+    if let Some(nonce) = &send_options.nonce {
+        if send_options.sign_only && send_options.nonce_used.swap(true, Ordering::Relaxed) {
+            anyhow::bail!(
+                "nonce account {} is already used by a transaction signed earlier, a durable \
+                 nonce only covers one transaction until it lands; sign fewer items at a time",
+                nonce.account
+            );
+        }
+        return nonce.blockhash(rpc_client).await;
+    }
+    if send_options.sign_only {
+        send_options
+            .progress
+            .status("Signing with the latest blockhash, the transaction expires in about a minute");
+    }
+    Ok(rpc_client.get_latest_blockhash().await?)
+}
+
+/// Sign a message with those of `keypairs` it requires, then send it, or export it to the
+/// progress in sign-only mode for the missing signers to add their signatures
+pub async fn sign_and_send_transaction(
+    rpc_client: &RpcClient,
+    send_options: &SendOptions,
+    message: VersionedMessage,
+    keypairs: &[&Keypair],
+    instructions: &[Instruction],
+) -> anyhow::Result<Signature> {
+    let mut transaction = VersionedTransaction {
+        signatures: vec![Signature::default(); message.header().num_required_signatures as usize],
+        message,
+    };
+    let mut keypairs = keypairs.to_vec();
+    keypairs.extend(
+        send_options
+            .nonce
+            .as_ref()
+            .and_then(|nonce| nonce.authority.keypair()),
+    );
+    offline::sign_transaction(&mut transaction, &keypairs);
+    let missing = offline::missing_signers(&transaction);
+    if send_options.sign_only {
+        send_options.progress.exported(
+            &offline::encode_transaction(&transaction, send_options.encoding)?,
+            &missing,
+        );
+        return Ok(transaction.signatures[0]);
+    }
+    // Simulation does not verify signatures, so dry runs go through without every keypair
+    if let Some(signer) = missing.first().filter(|_| !send_options.dry_run) {
+        anyhow::bail!(
+            "{} must sign but its keypair is missing, sign the transaction offline instead",
+            signer
+        );
+    }
+    send_transaction(
+        rpc_client,
+        send_options,
+        &transaction,
+        &keypairs,
+        instructions,
+    )
+    .await
+}
+
+async fn send_lookup_table_transaction(
+    rpc_client: &RpcClient,
+    keypair: &Keypair,
+    send_options: &SendOptions,
+    instructions: &[Instruction],
+) -> anyhow::Result<(Signature, Pubkey)> {
+    let extend_accounts = instructions
+        .iter()
+        .flat_map(|ix| ix.accounts.iter().map(|meta| meta.pubkey))
+        .collect::<HashSet<_>>();
+
+    let instructions = &send_options
+        .prepare_instructions(rpc_client, instructions)
+        .await?;
+
+    if send_options.dry_run {
+        return simulate_lookup_table_transaction(
+            rpc_client,
+            keypair,
+            send_options,
+            instructions,
+            extend_accounts,
+        )
+        .await;
+    }
+
+    let lut_account = if send_options.sign_only {
+        // Creating or extending a table sends transactions, so only a table that already
+        // holds every address can be used
+        let saved = utils::load_lookup_table_state(&send_options.lookup_table_state)?;
+        match utils::find_reusable_lookup_table(
+            rpc_client,
+            keypair.pubkey(),
+            send_options.lookup_table,
+            &saved,
+            &extend_accounts,
+        )
+        .await?
+        {
+            Some(reusable) if reusable.missing.is_empty() => {
+                utils::fetch_address_lookup_table(rpc_client, reusable.key).await?
+            }
+            _ => anyhow::bail!(
+                "signing only needs a lookup table already holding every address, create one by sending a transaction first"
+            ),
+        }
+    } else {
+        utils::get_or_create_lookup_table(
+            keypair,
+            rpc_client,
+            send_options.lookup_table,
+            &send_options.lookup_table_state,
+            extend_accounts,
+            send_options.nonce.as_ref(),
+            &send_options.compute_budget,
+            &*send_options.progress,
+        )
+        .await?
+    };
+    let lookup_table = lut_account.key;
+    let blockhash = recent_blockhash(rpc_client, send_options).await?;
+    let message = Message::try_compile(&keypair.pubkey(), instructions, &[lut_account], blockhash)?;
+    let signature = sign_and_send_transaction(
+        rpc_client,
+        send_options,
+        VersionedMessage::V0(message),
+        &[keypair],
+        instructions,
+    )
+    .await?;
+    Ok((signature, lookup_table))
+}
+
+/// Simulate a v0 transaction without creating or extending any lookup table.
+///
+/// A reusable table that already holds every address is simulated against directly.
+/// Otherwise the transaction is compiled against the table that would be created or
+/// extended, and only its size is estimated since the runtime cannot load that table yet.
+async fn simulate_lookup_table_transaction(
+    rpc_client: &RpcClient,
+    keypair: &Keypair,
+    send_options: &SendOptions,
+    instructions: &[Instruction],
+    extend_accounts: HashSet<Pubkey>,
+) -> anyhow::Result<(Signature, Pubkey)> {
+    let saved = utils::load_lookup_table_state(&send_options.lookup_table_state)?;
+    let reusable = utils::find_reusable_lookup_table(
+        rpc_client,
+        keypair.pubkey(),
+        send_options.lookup_table,
+        &saved,
+        &extend_accounts,
+    )
+    .await?;
+    let blockhash = recent_blockhash(rpc_client, send_options).await?;
+
+    let lut_account = match reusable {
+        Some(reusable) if reusable.missing.is_empty() => {
+            let lut_account = utils::fetch_address_lookup_table(rpc_client, reusable.key).await?;
+            let message =
+                Message::try_compile(&keypair.pubkey(), instructions, &[lut_account], blockhash)?;
+            let signature = sign_and_send_transaction(
+                rpc_client,
+                send_options,
+                VersionedMessage::V0(message),
+                &[keypair],
+                instructions,
+            )
+            .await?;
+            return Ok((signature, reusable.key));
+        }
+        Some(reusable) => {
+            send_options.progress.status(&format!(
+                "Would extend address lookup table {} with {} addresses",
+                reusable.key,
+                reusable.missing.len()
+            ));
+            let mut lut_account =
+                utils::fetch_address_lookup_table(rpc_client, reusable.key).await?;
+            lut_account.addresses.extend(reusable.missing);
+            lut_account
+        }
+        None => {
+            let recent_slot = rpc_client.get_slot().await?;
+            let key = derive_lookup_table_address(&keypair.pubkey(), recent_slot).0;
+            send_options.progress.status(&format!(
+                "Would create address lookup table {} with {} addresses",
+                key,
+                extend_accounts.len()
+            ));
+            AddressLookupTableAccount {
+                key,
+                addresses: extend_accounts.into_iter().collect(),
+            }
+        }
+    };
+
+    let lookup_table = lut_account.key;
+    let message = Message::try_compile(&keypair.pubkey(), instructions, &[lut_account], blockhash)?;
+    let mut transaction = VersionedTransaction {
+        signatures: vec![Signature::default(); message.header.num_required_signatures as usize],
+        message: VersionedMessage::V0(message),
+    };
+    offline::sign_transaction(&mut transaction, &[keypair]);
+    let size = transaction.signatures.len() * std::mem::size_of::<Signature>()
+        + transaction.message.serialize().len()
+        + 1;
+    send_options.progress.status(&format!(
+        "Skipped simulating txn {}: its lookup table does not exist yet",
+        transaction.signatures[0]
+    ));
+    send_options.progress.status(&format!(
+        "  instructions: {}, estimated size: {}/{} bytes",
+        instructions.len(),
+        size,
+        PACKET_DATA_SIZE
+    ));
+    if size > PACKET_DATA_SIZE {
+        anyhow::bail!("transaction would exceed the maximum transaction size");
+    }
+    Ok((transaction.signatures[0], lookup_table))
+}
+
+/// Send and confirm a transaction, or simulate it and report its effects in dry-run mode.
+/// `instructions` are the instructions the transaction was built from, whose writable
+/// accounts are reported on simulation.
+///
+/// An expired transaction is signed again by `keypairs` with a fresh blockhash, unless its
+/// blockhash was fixed in the options or by a durable nonce, or no keypair can sign it.
+/// Those are rebroadcast until they land, or until their blockhash or nonce is no longer
+/// valid.
+pub async fn send_transaction(
+    rpc_client: &RpcClient,
+    send_options: &SendOptions,
+    transaction: &VersionedTransaction,
+    keypairs: &[&Keypair],
+    instructions: &[Instruction],
+) -> anyhow::Result<Signature> {
+    if send_options.dry_run {
+        return simulate_transaction(rpc_client, send_options, transaction, instructions).await;
+    }
+
+    let refresh_blockhash =
+        send_options.blockhash.is_none() && send_options.nonce.is_none() && !keypairs.is_empty();
+    let signature = if refresh_blockhash {
+        let mut attempts = 0;
+        send::send_and_confirm(rpc_client, &SendConfig::default(), |blockhash| {
+            if attempts > 0 {
+                send_options
+                    .progress
+                    .status("Transaction expired, re-signing it with a fresh blockhash");
+            }
+            attempts += 1;
+            if blockhash == *transaction.message.recent_blockhash() {
+                return Ok(transaction.clone());
+            }
+            let mut message = transaction.message.clone();
+            message.set_recent_blockhash(blockhash);
+            let mut resigned = VersionedTransaction {
+                signatures: vec![Signature::default(); transaction.signatures.len()],
+                message,
+            };
+            offline::sign_transaction(&mut resigned, keypairs);
+            Ok(resigned)
+        })
+        .await?
+    } else {
+        let lifetime = match nonce::durable_nonce_account(transaction) {
+            Some(account) => Lifetime::DurableNonce(account),
+            None => Lifetime::Blockhash,
+        };
+        send::send_and_confirm_signed(rpc_client, &SendConfig::default(), transaction, lifetime)
+            .await?
+    };
+    send_options.progress.status(&format!(
+        "View confirmed txn at: https://solscan.io/tx/{}",
+        signature
+    ));
+    Ok(signature)
+}
+
+/// The outcome of a transaction simulated in dry-run mode
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub signature: Signature,
+    pub error: Option<TransactionError>,
+    pub units_consumed: Option<u64>,
+    pub logs: Vec<String>,
+    /// The writable accounts of the transaction that it would change
+    pub account_changes: Vec<AccountChange>,
+}
+
+/// How a simulated transaction would change an account
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccountChange {
+    Created {
+        address: Pubkey,
+        lamports: u64,
+        size: usize,
+        owner: Pubkey,
+    },
+    Closed {
+        address: Pubkey,
+        lamports: u64,
+    },
+    Changed {
+        address: Pubkey,
+        lamports: (u64, u64),
+        size: (usize, usize),
+    },
+}
+
+async fn simulate_transaction(
+    rpc_client: &RpcClient,
+    send_options: &SendOptions,
+    transaction: &impl SerializableTransaction,
+    instructions: &[Instruction],
+) -> anyhow::Result<Signature> {
+    let writable = compute_budget::writable_accounts(instructions);
+    let before = rpc_client.get_multiple_accounts(&writable).await?;
+    let result = rpc_client
+        .simulate_transaction_with_config(
+            transaction,
+            RpcSimulateTransactionConfig {
+                sig_verify: false,
+                replace_recent_blockhash: true,
+                accounts: Some(RpcSimulateTransactionAccountsConfig {
+                    encoding: Some(UiAccountEncoding::Base64),
+                    addresses: writable.iter().map(Pubkey::to_string).collect(),
+                }),
+                ..RpcSimulateTransactionConfig::default()
+            },
+        )
+        .await?
+        .value;
+
+    let after = result.accounts.unwrap_or_default();
+    let account_changes = writable
+        .iter()
+        .zip(before)
+        .zip(after)
+        .filter_map(|((address, before), after)| {
+            let after = after.and_then(|account| account.decode::<Account>());
+            match (before, after) {
+                (None, Some(after)) => Some(AccountChange::Created {
+                    address: *address,
+                    lamports: after.lamports,
+                    size: after.data.len(),
+                    owner: after.owner,
+                }),
+                (Some(before), None) => Some(AccountChange::Closed {
+                    address: *address,
+                    lamports: before.lamports,
+                }),
+                (Some(before), Some(after)) if before != after => Some(AccountChange::Changed {
+                    address: *address,
+                    lamports: (before.lamports, after.lamports),
+                    size: (before.data.len(), after.data.len()),
+                }),
+                _ => None,
+            }
+        })
+        .collect();
+    let signature = *transaction.get_signature();
+    send_options.progress.simulated(&Simulation {
+        signature,
+        error: result.err.clone(),
+        units_consumed: result.units_consumed,
+        logs: result.logs.unwrap_or_default(),
+        account_changes,
+    });
+
+    match result.err {
+        Some(err) => Err(anyhow::anyhow!("simulation failed: {}", err)),
+        None => Ok(signature),
+    }
+}
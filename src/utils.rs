@@ -1,45 +1,130 @@
+use crate::compute_budget::ComputeBudget;
+use crate::nonce::DurableNonce;
+use crate::progress::Progress;
+use crate::send::{self, Lifetime, SendConfig};
+use crate::transaction::{send_legacy_transaction, SendOptions};
+use anchor_lang::{AccountDeserialize, Discriminator};
+use anchor_spl::token_2022::spl_token_2022::extension::StateWithExtensions;
+use anchor_spl::token_2022::spl_token_2022::state::{Account as TokenAccount, Mint};
+use anyhow::Context;
+use referral::{REFERRAL_ATA_SEED, REFERRAL_SEED};
+use solana_account_decoder::{UiAccountEncoding, UiDataSliceConfig};
 use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig};
+use solana_client::rpc_filter::{Memcmp, RpcFilterType};
+use solana_sdk::account::{from_account, Account};
 use solana_sdk::address_lookup_table::{
-    instruction::{create_lookup_table, extend_lookup_table},
-    state::AddressLookupTable,
+    self,
+    instruction::{
+        close_lookup_table, create_lookup_table, deactivate_lookup_table, extend_lookup_table,
+    },
+    state::{AddressLookupTable, LookupTableStatus},
     AddressLookupTableAccount,
 };
+use solana_sdk::clock::Slot;
+use solana_sdk::instruction::Instruction;
 use solana_sdk::pubkey::Pubkey;
-use solana_sdk::signature::{Keypair, Signer};
-use solana_sdk::transaction::Transaction;
+use solana_sdk::signature::{Keypair, Signature, Signer};
+use solana_sdk::signer::SignerError;
+use solana_sdk::slot_hashes::SlotHashes;
+use solana_sdk::sysvar;
+use solana_sdk::transaction::{Transaction, VersionedTransaction};
 use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
 
 const DEFAULT_MAX_EXTEND_SIZE: usize = 20;
+/// Max number of addresses a LUT can contain
+pub const MAX_LUT_SIZE: usize = 256;
+/// Offset of the authority pubkey in a serialized lookup table, after the bincode
+/// enum tag, both slots, the start index and the option tag
+const LOOKUP_TABLE_AUTHORITY_OFFSET: usize = 22;
+/// Offset of the owner field in a token account
+const TOKEN_ACCOUNT_OWNER_OFFSET: usize = 32;
+/// Offset of the partner field in a referral account, after the discriminator
+const REFERRAL_ACCOUNT_PARTNER_OFFSET: usize = 8;
+/// Offset of the project field in a referral account
+const REFERRAL_ACCOUNT_PROJECT_OFFSET: usize = 40;
+/// Max number of deactivate or close instructions per lookup table transaction
+const MAX_LUT_INSTRUCTIONS_PER_TRANSACTION: usize = 10;
+/// Max number of accounts `getMultipleAccounts` accepts per request
+pub const MAX_MULTIPLE_ACCOUNTS: usize = 100;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn create_and_extend_lookup_table(
     keypair: &Keypair,
     rpc_client: &RpcClient,
     accounts: HashSet<Pubkey>,
     chunk_size: Option<usize>,
+    nonce: Option<&DurableNonce>,
+    compute_budget: &ComputeBudget,
+    progress: &dyn Progress,
 ) -> Result<Pubkey, anyhow::Error> {
     let accounts = accounts.into_iter().collect::<Vec<_>>();
-    let chunk_size = chunk_size
-        .map(|size| std::cmp::min(size, DEFAULT_MAX_EXTEND_SIZE))
-        .unwrap_or(DEFAULT_MAX_EXTEND_SIZE);
+    let alt_pubkey =
+        create_address_lookup_table(keypair, rpc_client, nonce, compute_budget, progress).await?;
+    extend_address_lookup_table(
+        keypair,
+        rpc_client,
+        alt_pubkey,
+        accounts,
+        chunk_size,
+        nonce,
+        compute_budget,
+        progress,
+    )
+    .await?;
 
-    let latest_blockhash = rpc_client.get_latest_blockhash().await?;
+    Ok(alt_pubkey)
+}
+
+pub async fn create_address_lookup_table(
+    keypair: &Keypair,
+    rpc_client: &RpcClient,
+    nonce: Option<&DurableNonce>,
+    compute_budget: &ComputeBudget,
+    progress: &dyn Progress,
+) -> anyhow::Result<Pubkey> {
     let recent_slot = rpc_client.get_slot().await?;
 
     let (create_ix, alt_pubkey) =
         create_lookup_table(keypair.pubkey(), keypair.pubkey(), recent_slot);
 
-    let mut transaction = Transaction::new_with_payer(&[create_ix], Some(&keypair.pubkey()));
-    transaction.try_sign(&[keypair], latest_blockhash)?;
+    let signature = send_lookup_table_instruction(
+        keypair,
+        rpc_client,
+        nonce,
+        compute_budget,
+        progress,
+        create_ix,
+    )
+    .await?;
 
-    let signature = rpc_client
-        .send_and_confirm_transaction(&transaction)
-        .await?;
+    progress.status(&format!(
+        "Address lookup table creation tx signature: {}",
+        signature
+    ));
+    progress.status(&format!("Address lookup table address: {}", alt_pubkey));
 
-    println!("Address lookup table creation tx signature: {}", signature);
-    println!("Address lookup table address: {}", alt_pubkey);
+    Ok(alt_pubkey)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn extend_address_lookup_table(
+    keypair: &Keypair,
+    rpc_client: &RpcClient,
+    alt_pubkey: Pubkey,
+    accounts: Vec<Pubkey>,
+    chunk_size: Option<usize>,
+    nonce: Option<&DurableNonce>,
+    compute_budget: &ComputeBudget,
+    progress: &dyn Progress,
+) -> anyhow::Result<()> {
+    let chunk_size = chunk_size
+        .map(|size| std::cmp::min(size, DEFAULT_MAX_EXTEND_SIZE))
+        .unwrap_or(DEFAULT_MAX_EXTEND_SIZE);
 
     for chunk in accounts.chunks(chunk_size) {
-        let latest_blockhash = rpc_client.get_latest_blockhash().await?;
         let extend_ix = extend_lookup_table(
             alt_pubkey,
             keypair.pubkey(),
@@ -47,16 +132,211 @@ pub async fn create_and_extend_lookup_table(
             chunk.to_vec(),
         );
 
-        let mut transaction = Transaction::new_with_payer(&[extend_ix], Some(&keypair.pubkey()));
-        transaction.try_sign(&[keypair], latest_blockhash)?;
+        let signature = send_lookup_table_instruction(
+            keypair,
+            rpc_client,
+            nonce,
+            compute_budget,
+            progress,
+            extend_ix,
+        )
+        .await?;
+        progress.status(&format!(
+            "Extended Address lookup table tx signature: {}",
+            signature
+        ));
+    }
+
+    Ok(())
+}
+
+/// Send a lookup table instruction paid by the keypair, with its compute budget, and
+/// advancing `nonce` when given
+async fn send_lookup_table_instruction(
+    keypair: &Keypair,
+    rpc_client: &RpcClient,
+    nonce: Option<&DurableNonce>,
+    compute_budget: &ComputeBudget,
+    progress: &dyn Progress,
+    instruction: Instruction,
+) -> anyhow::Result<Signature> {
+    let instructions = compute_budget
+        .with_budget(rpc_client, &[instruction], progress)
+        .await?;
+    let (instructions, nonce_blockhash) = match nonce {
+        Some(nonce) => (
+            nonce.with_advance(&instructions),
+            Some(nonce.blockhash(rpc_client).await?),
+        ),
+        None => (instructions, None),
+    };
+    let mut signers = vec![keypair];
+    match nonce.map(|nonce| nonce.authority.keypair()) {
+        Some(Some(authority)) if authority.pubkey() != keypair.pubkey() => signers.push(authority),
+        Some(None) => anyhow::bail!("lookup table transactions need the nonce authority keypair"),
+        _ => {}
+    }
 
-        let signature = rpc_client
-            .send_and_confirm_transaction(&transaction)
+    let mut attempts = 0;
+    let mut sign = |blockhash| {
+        if attempts > 0 {
+            progress.status("Transaction expired, re-signing it with a fresh blockhash");
+        }
+        attempts += 1;
+        let mut transaction = Transaction::new_with_payer(&instructions, Some(&keypair.pubkey()));
+        transaction.try_sign(&signers, blockhash)?;
+        Ok::<VersionedTransaction, SignerError>(transaction.into())
+    };
+    let signature = match (nonce, nonce_blockhash) {
+        (Some(nonce), Some(nonce_blockhash)) => {
+            send::send_and_confirm_signed(
+                rpc_client,
+                &SendConfig::default(),
+                &sign(nonce_blockhash)?,
+                Lifetime::DurableNonce(nonce.account),
+            )
+            .await?
+        }
+        _ => send::send_and_confirm(rpc_client, &SendConfig::default(), sign).await?,
+    };
+    Ok(signature)
+}
+
+/// Get a lookup table containing every one of `accounts`.
+///
+/// An explicit `lookup_table`, or otherwise the tables recorded in the state file at
+/// `state_path`, are reused and extended with only the missing addresses. A new table is
+/// created and recorded when none of them is usable or has enough room left.
+#[allow(clippy::too_many_arguments)]
+pub async fn get_or_create_lookup_table(
+    keypair: &Keypair,
+    rpc_client: &RpcClient,
+    lookup_table: Option<Pubkey>,
+    state_path: &Path,
+    accounts: HashSet<Pubkey>,
+    nonce: Option<&DurableNonce>,
+    compute_budget: &ComputeBudget,
+    progress: &dyn Progress,
+) -> anyhow::Result<AddressLookupTableAccount> {
+    let mut saved = load_lookup_table_state(state_path)?;
+    if let Some(reusable) = find_reusable_lookup_table(
+        rpc_client,
+        keypair.pubkey(),
+        lookup_table,
+        &saved,
+        &accounts,
+    )
+    .await?
+    {
+        if !reusable.missing.is_empty() {
+            extend_address_lookup_table(
+                keypair,
+                rpc_client,
+                reusable.key,
+                reusable.missing,
+                None,
+                nonce,
+                compute_budget,
+                progress,
+            )
             .await?;
-        println!("Extended Address lookup table tx signature: {}", signature);
+        }
+        progress.status(&format!("Reusing address lookup table: {}", reusable.key));
+        return fetch_address_lookup_table(rpc_client, reusable.key).await;
     }
 
-    Ok(alt_pubkey)
+    let alt_pubkey =
+        create_address_lookup_table(keypair, rpc_client, nonce, compute_budget, progress).await?;
+    saved.push(alt_pubkey);
+    save_lookup_table_state(state_path, &saved)?;
+    extend_address_lookup_table(
+        keypair,
+        rpc_client,
+        alt_pubkey,
+        accounts.into_iter().collect(),
+        None,
+        nonce,
+        compute_budget,
+        progress,
+    )
+    .await?;
+    fetch_address_lookup_table(rpc_client, alt_pubkey).await
+}
+
+/// A lookup table that can be reused, and the addresses it still lacks
+pub struct ReusableLookupTable {
+    pub key: Pubkey,
+    pub missing: Vec<Pubkey>,
+}
+
+/// Find an active lookup table owned by `authority` with room for every one of `accounts`,
+/// checking an explicit `lookup_table` or otherwise the `saved` tables, newest first.
+pub async fn find_reusable_lookup_table(
+    rpc_client: &RpcClient,
+    authority: Pubkey,
+    lookup_table: Option<Pubkey>,
+    saved: &[Pubkey],
+    accounts: &HashSet<Pubkey>,
+) -> anyhow::Result<Option<ReusableLookupTable>> {
+    let candidates = match lookup_table {
+        Some(lookup_table) => vec![lookup_table],
+        None => saved.iter().rev().copied().collect(),
+    };
+
+    for candidate in candidates {
+        let raw = match rpc_client.get_account_data(&candidate).await {
+            Ok(raw) => raw,
+            Err(_) if lookup_table.is_none() => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let table = AddressLookupTable::deserialize(&raw)?;
+        let usable =
+            table.meta.authority == Some(authority) && table.meta.deactivation_slot == Slot::MAX;
+        let missing = accounts
+            .iter()
+            .filter(|account| !table.addresses.contains(account))
+            .copied()
+            .collect::<Vec<_>>();
+        let has_room = table.addresses.len() + missing.len() <= MAX_LUT_SIZE;
+
+        if usable && has_room {
+            return Ok(Some(ReusableLookupTable {
+                key: candidate,
+                missing,
+            }));
+        }
+        if lookup_table.is_some() {
+            anyhow::bail!(
+                "lookup table {} cannot be reused: it is inactive, not owned by {}, or lacks room for {} more addresses",
+                candidate,
+                authority,
+                missing.len()
+            );
+        }
+    }
+
+    Ok(None)
+}
+
+/// Load the lookup tables previously created by the CLI, oldest first
+pub fn load_lookup_table_state(path: &Path) -> anyhow::Result<Vec<Pubkey>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    serde_json::from_str::<Vec<String>>(&std::fs::read_to_string(path)?)?
+        .iter()
+        .map(|key| Pubkey::from_str(key).with_context(|| format!("invalid lookup table {}", key)))
+        .collect()
+}
+
+/// Record the lookup tables created by the CLI so later runs can reuse them
+pub fn save_lookup_table_state(path: &Path, lookup_tables: &[Pubkey]) -> anyhow::Result<()> {
+    let keys = lookup_tables
+        .iter()
+        .map(Pubkey::to_string)
+        .collect::<Vec<_>>();
+    std::fs::write(path, serde_json::to_string_pretty(&keys)?)
+        .with_context(|| format!("failed to write lookup table state to {}", path.display()))
 }
 
 pub async fn fetch_address_lookup_table(
@@ -71,27 +351,485 @@ pub async fn fetch_address_lookup_table(
     })
 }
 
-#[allow(dead_code)]
-pub async fn fetch_address_lookup_tables(
+/// A lookup table together with its lifecycle status
+#[derive(Debug, Clone)]
+pub struct LookupTableInfo {
+    pub key: Pubkey,
+    pub addresses: Vec<Pubkey>,
+    pub status: LookupTableStatus,
+}
+
+/// Fetch every lookup table whose authority is `authority`
+pub async fn fetch_lookup_tables_by_authority(
     rpc_client: &RpcClient,
-    alts: impl Iterator<Item = &Pubkey>,
-) -> anyhow::Result<Vec<AddressLookupTableAccount>> {
-    let keys = alts.copied().collect::<Vec<_>>();
-    Ok(rpc_client
-        .get_multiple_accounts(&keys)
+    authority: Pubkey,
+) -> anyhow::Result<Vec<LookupTableInfo>> {
+    let config = RpcProgramAccountsConfig {
+        filters: Some(vec![RpcFilterType::Memcmp(Memcmp::new_base58_encoded(
+            LOOKUP_TABLE_AUTHORITY_OFFSET,
+            authority.as_ref(),
+        ))]),
+        account_config: RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            ..RpcAccountInfoConfig::default()
+        },
+        ..RpcProgramAccountsConfig::default()
+    };
+    let accounts = rpc_client
+        .get_program_accounts_with_config(&address_lookup_table::program::ID, config)
+        .await?;
+    let (current_slot, slot_hashes) = fetch_slot_hashes(rpc_client).await?;
+
+    accounts
+        .into_iter()
+        .map(|(key, account)| lookup_table_info(key, &account.data, current_slot, &slot_hashes))
+        .collect()
+}
+
+/// Fetch the given lookup tables with their lifecycle status, failing on missing tables
+pub async fn fetch_lookup_table_infos(
+    rpc_client: &RpcClient,
+    keys: &[Pubkey],
+) -> anyhow::Result<Vec<LookupTableInfo>> {
+    let (current_slot, slot_hashes) = fetch_slot_hashes(rpc_client).await?;
+    let mut infos = Vec::with_capacity(keys.len());
+    for chunk in keys.chunks(MAX_MULTIPLE_ACCOUNTS) {
+        let accounts = rpc_client.get_multiple_accounts(chunk).await?;
+        for (key, account) in chunk.iter().zip(accounts) {
+            let account = account.with_context(|| format!("lookup table {} not found", key))?;
+            infos.push(lookup_table_info(
+                *key,
+                &account.data,
+                current_slot,
+                &slot_hashes,
+            )?);
+        }
+    }
+    Ok(infos)
+}
+
+fn lookup_table_info(
+    key: Pubkey,
+    data: &[u8],
+    current_slot: Slot,
+    slot_hashes: &SlotHashes,
+) -> anyhow::Result<LookupTableInfo> {
+    let table = AddressLookupTable::deserialize(data)
+        .with_context(|| format!("failed to decode lookup table {}", key))?;
+    Ok(LookupTableInfo {
+        key,
+        addresses: table.addresses.to_vec(),
+        status: table.meta.status(current_slot, slot_hashes),
+    })
+}
+
+/// The lookup tables deactivated or closed by [`deactivate_lookup_tables`] and
+/// [`close_lookup_tables`]
+#[derive(Debug, Default)]
+pub struct LookupTableChanges {
+    pub changed: Vec<Pubkey>,
+    /// The tables left as they are because of their status
+    pub skipped: Vec<LookupTableInfo>,
+    pub signatures: Vec<Signature>,
+}
+
+/// Deactivate those of `tables` that are still active, the first step to closing them
+pub async fn deactivate_lookup_tables(
+    rpc_client: &RpcClient,
+    keypair: &Keypair,
+    send_options: &SendOptions,
+    tables: Vec<LookupTableInfo>,
+) -> anyhow::Result<LookupTableChanges> {
+    let (active, skipped): (Vec<_>, Vec<_>) = tables
+        .into_iter()
+        .partition(|table| table.status == LookupTableStatus::Activated);
+    let changed = active.iter().map(|table| table.key).collect::<Vec<_>>();
+    let instructions = changed
+        .iter()
+        .map(|table| deactivate_lookup_table(*table, keypair.pubkey()))
+        .collect::<Vec<_>>();
+    let signatures =
+        send_lookup_table_instructions(rpc_client, keypair, send_options, &instructions).await?;
+    Ok(LookupTableChanges {
+        changed,
+        skipped,
+        signatures,
+    })
+}
+
+/// Close those of `tables` whose deactivation has cooled down, refunding their rent to the
+/// keypair, and drop them from the state file once closed
+pub async fn close_lookup_tables(
+    rpc_client: &RpcClient,
+    keypair: &Keypair,
+    send_options: &SendOptions,
+    tables: Vec<LookupTableInfo>,
+) -> anyhow::Result<LookupTableChanges> {
+    let (closable, skipped): (Vec<_>, Vec<_>) = tables
+        .into_iter()
+        .partition(|table| table.status == LookupTableStatus::Deactivated);
+    let changed = closable.iter().map(|table| table.key).collect::<Vec<_>>();
+    let instructions = changed
+        .iter()
+        .map(|table| close_lookup_table(*table, keypair.pubkey(), keypair.pubkey()))
+        .collect::<Vec<_>>();
+    let signatures =
+        send_lookup_table_instructions(rpc_client, keypair, send_options, &instructions).await?;
+
+    let saved = load_lookup_table_state(&send_options.lookup_table_state)?;
+    if send_options.submits() && saved.iter().any(|table| changed.contains(table)) {
+        let remaining = saved
+            .into_iter()
+            .filter(|table| !changed.contains(table))
+            .collect::<Vec<_>>();
+        save_lookup_table_state(&send_options.lookup_table_state, &remaining)?;
+    }
+    Ok(LookupTableChanges {
+        changed,
+        skipped,
+        signatures,
+    })
+}
+
+/// Send deactivate or close instructions, batched in legacy transactions
+async fn send_lookup_table_instructions(
+    rpc_client: &RpcClient,
+    keypair: &Keypair,
+    send_options: &SendOptions,
+    instructions: &[Instruction],
+) -> anyhow::Result<Vec<Signature>> {
+    let mut signatures = vec![];
+    for instructions in instructions.chunks(MAX_LUT_INSTRUCTIONS_PER_TRANSACTION) {
+        signatures
+            .push(send_legacy_transaction(rpc_client, keypair, send_options, instructions).await?);
+    }
+    Ok(signatures)
+}
+
+/// Fetch the current slot and the slot hashes sysvar, which decide whether a deactivated
+/// lookup table has cooled down
+async fn fetch_slot_hashes(rpc_client: &RpcClient) -> anyhow::Result<(Slot, SlotHashes)> {
+    let current_slot = rpc_client.get_slot().await?;
+    let account = rpc_client.get_account(&sysvar::slot_hashes::ID).await?;
+    let slot_hashes = from_account::<SlotHashes, _>(&account)
+        .context("failed to decode the slot hashes sysvar")?;
+    Ok((current_slot, slot_hashes))
+}
+
+/// The parts of a mint the CLI cares about
+#[derive(Debug, Clone, Copy)]
+pub struct MintInfo {
+    /// The owning token program, SPL Token or Token-2022
+    pub token_program: Pubkey,
+    pub decimals: u8,
+}
+
+/// Fetch and decode every mint, returned in the same order as `mints`.
+pub async fn fetch_mints(
+    rpc_client: &RpcClient,
+    mints: &[Pubkey],
+) -> anyhow::Result<Vec<MintInfo>> {
+    try_fetch_mints(rpc_client, mints)
         .await?
         .into_iter()
-        .zip(keys.into_iter())
-        .filter_map(|(opt, key)| {
-            opt.map::<Option<_>, _>(|acc| {
-                Some(AddressLookupTableAccount {
-                    key,
-                    addresses: AddressLookupTable::deserialize(&acc.data)
-                        .ok()
-                        .map(|data| data.addresses.to_vec())?,
-                })
-            })
+        .collect()
+}
+
+/// Fetch and decode every mint like [`fetch_mints`], with an error in place of each mint
+/// that is missing or not a token mint rather than failing them all.
+pub async fn try_fetch_mints(
+    rpc_client: &RpcClient,
+    mints: &[Pubkey],
+) -> anyhow::Result<Vec<anyhow::Result<MintInfo>>> {
+    let mut infos = Vec::with_capacity(mints.len());
+    for chunk in mints.chunks(MAX_MULTIPLE_ACCOUNTS) {
+        let accounts = rpc_client.get_multiple_accounts(chunk).await?;
+        for (mint, account) in chunk.iter().zip(accounts) {
+            infos.push(mint_info(*mint, account));
+        }
+    }
+    Ok(infos)
+}
+
+fn mint_info(mint: Pubkey, account: Option<Account>) -> anyhow::Result<MintInfo> {
+    let account = account.with_context(|| format!("mint {} not found", mint))?;
+    if account.owner != anchor_spl::token::ID && account.owner != anchor_spl::token_2022::ID {
+        anyhow::bail!(
+            "mint {} is owned by {}, which is not a token program",
+            mint,
+            account.owner
+        );
+    }
+    let state = StateWithExtensions::<Mint>::unpack(&account.data)
+        .with_context(|| format!("failed to decode mint {}", mint))?;
+    Ok(MintInfo {
+        token_program: account.owner,
+        decimals: state.base.decimals,
+    })
+}
+
+/// A referral token account found on-chain
+#[derive(Debug, Clone, Copy)]
+pub struct ReferralTokenAccount {
+    pub address: Pubkey,
+    pub mint: Pubkey,
+    pub token_program: Pubkey,
+    pub amount: u64,
+}
+
+/// Find every referral token account of `referral_account`, under both token programs.
+///
+/// Referral token accounts are owned by the project, so this scans all token accounts
+/// whose owner is `project` and keeps those sitting at the referral token-account PDA
+/// of their mint.
+pub async fn fetch_referral_token_accounts(
+    rpc_client: &RpcClient,
+    program: Pubkey,
+    project: Pubkey,
+    referral_account: Pubkey,
+) -> anyhow::Result<Vec<ReferralTokenAccount>> {
+    let mut token_accounts = vec![];
+    for token_program in [anchor_spl::token::ID, anchor_spl::token_2022::ID] {
+        let config = RpcProgramAccountsConfig {
+            filters: Some(vec![
+                RpcFilterType::TokenAccountState,
+                RpcFilterType::Memcmp(Memcmp::new_base58_encoded(
+                    TOKEN_ACCOUNT_OWNER_OFFSET,
+                    project.as_ref(),
+                )),
+            ]),
+            account_config: RpcAccountInfoConfig {
+                encoding: Some(UiAccountEncoding::Base64),
+                ..RpcAccountInfoConfig::default()
+            },
+            ..RpcProgramAccountsConfig::default()
+        };
+        let accounts = rpc_client
+            .get_program_accounts_with_config(&token_program, config)
+            .await?;
+        for (address, account) in accounts {
+            let state = StateWithExtensions::<TokenAccount>::unpack(&account.data)
+                .with_context(|| format!("failed to decode token account {}", address))?;
+            let mint = state.base.mint;
+            if address == find_referral_token_account(program, referral_account, mint) {
+                token_accounts.push(ReferralTokenAccount {
+                    address,
+                    mint,
+                    token_program,
+                    amount: state.base.amount,
+                });
+            }
+        }
+    }
+    Ok(token_accounts)
+}
+
+/// The balance of a referral token account
+#[derive(Debug, Clone, Copy)]
+pub struct ReferralTokenBalance {
+    pub mint: Pubkey,
+    pub address: Pubkey,
+    pub decimals: u8,
+    /// `None` when the referral token account does not exist
+    pub amount: Option<u64>,
+}
+
+/// Fetch the balances of the referral token accounts of `referral_account`, either for
+/// every mint in `mints` or, without mints, for every account found by scanning the
+/// token accounts of `project`.
+pub async fn fetch_referral_token_balances(
+    rpc_client: &RpcClient,
+    program: Pubkey,
+    project: Pubkey,
+    referral_account: Pubkey,
+    mints: Option<&[Pubkey]>,
+) -> anyhow::Result<Vec<ReferralTokenBalance>> {
+    let (mints, addresses, amounts) = match mints {
+        Some(mints) => {
+            let addresses = mints
+                .iter()
+                .map(|mint| find_referral_token_account(program, referral_account, *mint))
+                .collect::<Vec<_>>();
+            let mut amounts = Vec::with_capacity(addresses.len());
+            for chunk in addresses.chunks(MAX_MULTIPLE_ACCOUNTS) {
+                for (address, account) in chunk
+                    .iter()
+                    .zip(rpc_client.get_multiple_accounts(chunk).await?)
+                {
+                    let amount = account
+                        .map(|account| {
+                            StateWithExtensions::<TokenAccount>::unpack(&account.data)
+                                .map(|state| state.base.amount)
+                                .with_context(|| {
+                                    format!("failed to decode token account {}", address)
+                                })
+                        })
+                        .transpose()?;
+                    amounts.push(amount);
+                }
+            }
+            (mints.to_vec(), addresses, amounts)
+        }
+        None => {
+            let token_accounts =
+                fetch_referral_token_accounts(rpc_client, program, project, referral_account)
+                    .await?;
+            (
+                token_accounts.iter().map(|account| account.mint).collect(),
+                token_accounts
+                    .iter()
+                    .map(|account| account.address)
+                    .collect(),
+                token_accounts
+                    .iter()
+                    .map(|account| Some(account.amount))
+                    .collect(),
+            )
+        }
+    };
+    let mint_infos = fetch_mints(rpc_client, &mints).await?;
+
+    Ok(mints
+        .into_iter()
+        .zip(addresses)
+        .zip(amounts)
+        .zip(mint_infos)
+        .map(
+            |(((mint, address), amount), mint_info)| ReferralTokenBalance {
+                mint,
+                address,
+                decimals: mint_info.decimals,
+                amount,
+            },
+        )
+        .collect())
+}
+
+/// Derive the referral token-account PDA of `referral_account` for `mint`
+pub fn find_referral_token_account(
+    program: Pubkey,
+    referral_account: Pubkey,
+    mint: Pubkey,
+) -> Pubkey {
+    find_referral_token_account_with_bump(program, referral_account, mint).0
+}
+
+pub fn find_referral_token_account_with_bump(
+    program: Pubkey,
+    referral_account: Pubkey,
+    mint: Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[REFERRAL_ATA_SEED, referral_account.as_ref(), mint.as_ref()],
+        &program,
+    )
+}
+
+/// Derive the PDA of the referral account of `project` named `name`, which must have been
+/// validated as a seed
+pub fn find_referral_account(program: Pubkey, project: Pubkey, name: &str) -> Pubkey {
+    find_referral_account_with_bump(program, project, name).0
+}
+
+pub fn find_referral_account_with_bump(
+    program: Pubkey,
+    project: Pubkey,
+    name: &str,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[REFERRAL_SEED, project.as_ref(), name.as_bytes()],
+        &program,
+    )
+}
+
+/// Check which of `addresses` exist on-chain, returned in the same order.
+pub async fn fetch_accounts_exist(
+    rpc_client: &RpcClient,
+    addresses: &[Pubkey],
+) -> anyhow::Result<Vec<bool>> {
+    let mut exists = Vec::with_capacity(addresses.len());
+    for chunk in addresses.chunks(MAX_MULTIPLE_ACCOUNTS) {
+        let accounts = rpc_client.get_multiple_accounts(chunk).await?;
+        exists.extend(accounts.iter().map(Option::is_some));
+    }
+    Ok(exists)
+}
+
+/// Fetch every referral account of the referral program, optionally narrowed down to a
+/// partner and/or a project
+pub async fn fetch_referral_accounts(
+    rpc_client: &RpcClient,
+    program: Pubkey,
+    partner: Option<Pubkey>,
+    project: Option<Pubkey>,
+) -> anyhow::Result<Vec<(Pubkey, referral::ReferralAccount)>> {
+    let config = RpcProgramAccountsConfig {
+        filters: Some(referral_account_filters(partner, project)),
+        account_config: RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            ..RpcAccountInfoConfig::default()
+        },
+        ..RpcProgramAccountsConfig::default()
+    };
+
+    rpc_client
+        .get_program_accounts_with_config(&program, config)
+        .await?
+        .into_iter()
+        .map(|(address, account)| {
+            let referral_account =
+                referral::ReferralAccount::try_deserialize(&mut &account.data[..])
+                    .with_context(|| format!("failed to decode referral account {}", address))?;
+            Ok((address, referral_account))
         })
-        .flatten()
-        .collect::<Vec<_>>())
+        .collect()
+}
+
+/// Count the referral accounts [`fetch_referral_accounts`] would return, without
+/// downloading their data
+pub async fn count_referral_accounts(
+    rpc_client: &RpcClient,
+    program: Pubkey,
+    partner: Option<Pubkey>,
+    project: Option<Pubkey>,
+) -> anyhow::Result<usize> {
+    let config = RpcProgramAccountsConfig {
+        filters: Some(referral_account_filters(partner, project)),
+        account_config: RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            data_slice: Some(UiDataSliceConfig {
+                offset: 0,
+                length: 0,
+            }),
+            ..RpcAccountInfoConfig::default()
+        },
+        ..RpcProgramAccountsConfig::default()
+    };
+    Ok(rpc_client
+        .get_program_accounts_with_config(&program, config)
+        .await?
+        .len())
+}
+
+fn referral_account_filters(
+    partner: Option<Pubkey>,
+    project: Option<Pubkey>,
+) -> Vec<RpcFilterType> {
+    let mut filters = vec![RpcFilterType::Memcmp(Memcmp::new_base58_encoded(
+        0,
+        &referral::ReferralAccount::DISCRIMINATOR,
+    ))];
+    if let Some(partner) = partner {
+        filters.push(RpcFilterType::Memcmp(Memcmp::new_base58_encoded(
+            REFERRAL_ACCOUNT_PARTNER_OFFSET,
+            partner.as_ref(),
+        )));
+    }
+    if let Some(project) = project {
+        filters.push(RpcFilterType::Memcmp(Memcmp::new_base58_encoded(
+            REFERRAL_ACCOUNT_PROJECT_OFFSET,
+            project.as_ref(),
+        )));
+    }
+    filters
 }
@@ -0,0 +1,834 @@
+//! End to end tests of the CLI and library against a local test validator running the
+//! referral program.
+//!
+//! The program is loaded from `tests/fixtures/referral.so`, or from `SBF_OUT_DIR` when set.
+//! Dump the deployed one with
+//! `solana program dump -um REFER4ZgmyYx9c6He5XfaTMiGfdLwRnkV4RPp9t9iF3 tests/fixtures/referral.so`
+//! or copy it from an `anchor build` of the program. The tests are ignored by default since
+//! they need the program: run them with `cargo test -- --include-ignored`, and they fail
+//! when it is missing.
+
+use anchor_lang::AccountSerialize;
+use anchor_spl::token_2022::spl_token_2022;
+use anchor_spl::token_2022::spl_token_2022::extension::StateWithExtensions;
+use anchor_spl::token_2022::spl_token_2022::state::{Account as TokenAccount, Mint};
+use referral_client::compute_budget::{ComputeBudget, PriorityFee};
+use referral_client::progress::Silent;
+use referral_client::{name, utils, ReferralClient};
+use serde_json::Value;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::account::{Account, AccountSharedData};
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::instruction::Instruction;
+use solana_sdk::native_token::sol_to_lamports;
+use solana_sdk::program_pack::Pack;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::rent::Rent;
+use solana_sdk::signature::{write_keypair_file, Keypair};
+use solana_sdk::signer::Signer;
+use solana_sdk::system_instruction;
+use solana_sdk::transaction::Transaction;
+use solana_test_validator::{TestValidator, TestValidatorGenesis};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::str::FromStr;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+const DEFAULT_SHARE_BPS: u16 = 2_500;
+const NO_COMPUTE_BUDGET: ComputeBudget = ComputeBudget {
+    unit_limit: None,
+    priority_fee: PriorityFee::None,
+};
+
+/// A validator with the referral program and a project administered by `admin`, which is
+/// also the keypair of the CLI
+struct TestEnv {
+    validator: TestValidator,
+    rpc_client: Arc<RpcClient>,
+    client: ReferralClient,
+    admin: Keypair,
+    project: Pubkey,
+    dir: TempDir,
+}
+
+impl TestEnv {
+    /// Start the validator, failing when the referral program is not available
+    async fn start() -> Self {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/referral.so");
+        assert!(
+            std::env::var_os("SBF_OUT_DIR").is_some() || fixture.is_file(),
+            "{} is missing and SBF_OUT_DIR is not set, see the docs of tests/referral.rs",
+            fixture.display()
+        );
+
+        let admin = Keypair::new();
+        let project = Pubkey::new_unique();
+
+        let mut data = vec![];
+        referral::Project {
+            admin: admin.pubkey(),
+            name: "test".to_string(),
+            default_share_bps: DEFAULT_SHARE_BPS,
+        }
+        .try_serialize(&mut data)
+        .unwrap();
+        let project_account = Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner: referral::ID,
+            executable: false,
+            rent_epoch: 0,
+        };
+
+        let mut genesis = TestValidatorGenesis::default();
+        genesis
+            .add_program("referral", referral::ID)
+            .add_account(project, AccountSharedData::from(project_account))
+            .add_account(
+                admin.pubkey(),
+                AccountSharedData::new(sol_to_lamports(100.0), 0, &solana_sdk::system_program::ID),
+            );
+        let (validator, _) = genesis.start_async().await;
+
+        let rpc_client = Arc::new(RpcClient::new_with_commitment(
+            validator.rpc_url(),
+            CommitmentConfig::confirmed(),
+        ));
+        let client = ReferralClient::new(rpc_client.clone(), referral::ID);
+        let dir = TempDir::new().unwrap();
+        write_keypair_file(&admin, dir.path().join("admin.json")).unwrap();
+
+        TestEnv {
+            validator,
+            rpc_client,
+            client,
+            admin,
+            project,
+            dir,
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    /// Run a subcommand of the CLI and return its json document
+    fn cli(&self, args: &[&str]) -> Value {
+        let output = self.run(args);
+        assert!(
+            output.status.success(),
+            "{:?} failed:\n{}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        serde_json::from_slice(&output.stdout).unwrap()
+    }
+
+    /// Run a subcommand of the CLI that must fail and return its error output
+    fn cli_error(&self, args: &[&str]) -> String {
+        let output = self.run(args);
+        assert!(!output.status.success(), "{:?} succeeded", args);
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_referral-rs"))
+            // Keep a developer's .env out of the way
+            .current_dir(self.dir.path())
+            .env("HTTP_URL", self.validator.rpc_url())
+            .env("KEYPAIR", self.path("admin.json"))
+            .env("PROJECT", self.project.to_string())
+            .env("REFERRAL_PROGRAM", referral::ID.to_string())
+            .env("LOOKUP_TABLE_STATE", self.path("lookup-tables.json"))
+            .args(["--output", "json"])
+            .args(args)
+            .output()
+            .unwrap()
+    }
+
+    async fn send(&self, instructions: &[Instruction], signers: &[&Keypair]) {
+        let mut keypairs = vec![&self.admin];
+        keypairs.extend(signers);
+        let transaction = Transaction::new_signed_with_payer(
+            instructions,
+            Some(&self.admin.pubkey()),
+            &keypairs,
+            self.rpc_client.get_latest_blockhash().await.unwrap(),
+        );
+        self.rpc_client
+            .send_and_confirm_transaction(&transaction)
+            .await
+            .unwrap();
+    }
+
+    /// Create a mint of `token_program` whose authority is the admin
+    async fn create_mint(&self, token_program: Pubkey) -> Pubkey {
+        let mint = Keypair::new();
+        let lamports = self
+            .rpc_client
+            .get_minimum_balance_for_rent_exemption(Mint::LEN)
+            .await
+            .unwrap();
+        self.send(
+            &[
+                system_instruction::create_account(
+                    &self.admin.pubkey(),
+                    &mint.pubkey(),
+                    lamports,
+                    Mint::LEN as u64,
+                    &token_program,
+                ),
+                spl_token_2022::instruction::initialize_mint2(
+                    &token_program,
+                    &mint.pubkey(),
+                    &self.admin.pubkey(),
+                    None,
+                    6,
+                )
+                .unwrap(),
+            ],
+            &[&mint],
+        )
+        .await;
+        mint.pubkey()
+    }
+
+    /// Write the mints file read by the token-account subcommands
+    fn mints_file(&self, name: &str, mints: &[Pubkey]) -> String {
+        let path = self.path(name);
+        std::fs::write(&path, serde_json::to_string(&mints_json(mints)).unwrap()).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    /// Mint `amount` of a mint of the SPL Token program to `account`
+    async fn mint_to(&self, mint: Pubkey, account: Pubkey, amount: u64) {
+        self.send(
+            &[spl_token_2022::instruction::mint_to(
+                &anchor_spl::token::ID,
+                &mint,
+                &account,
+                &self.admin.pubkey(),
+                &[],
+                amount,
+            )
+            .unwrap()],
+            &[],
+        )
+        .await;
+    }
+
+    async fn token_account(&self, address: Pubkey) -> (Pubkey, TokenAccount) {
+        let account = self.rpc_client.get_account(&address).await.unwrap();
+        let state = StateWithExtensions::<TokenAccount>::unpack(&account.data).unwrap();
+        (account.owner, state.base)
+    }
+
+    async fn token_balance(&self, address: Pubkey) -> u64 {
+        self.token_account(address).await.1.amount
+    }
+}
+
+fn mints_json(mints: &[Pubkey]) -> Vec<String> {
+    mints.iter().map(ToString::to_string).collect()
+}
+
+fn pubkey(value: &Value) -> Pubkey {
+    Pubkey::from_str(value.as_str().unwrap()).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs the referral program, run with --include-ignored"]
+async fn referral_account_lifecycle() {
+    let env = TestEnv::start().await;
+    let partner = Pubkey::new_unique();
+
+    // Named account, at the PDA of its name
+    let created = env.cli(&[
+        "create-referral-account",
+        "alice",
+        "--partner",
+        &partner.to_string(),
+    ]);
+    let named = pubkey(&created["referral_account"]);
+    assert_eq!(
+        named,
+        env.client
+            .find_referral_account(env.project, "alice")
+            .unwrap()
+    );
+    let account = env.client.fetch_referral_account(named).await.unwrap();
+    assert_eq!(account.name.as_deref(), Some("alice"));
+    assert_eq!(account.partner, partner);
+    assert_eq!(account.project, env.project);
+    assert_eq!(account.share_bps, DEFAULT_SHARE_BPS);
+
+    let derived = env.cli(&["derive", "referral-account", "alice"]);
+    assert_eq!(pubkey(&derived["address"]), named);
+
+    // The program takes names as long as a seed can be
+    let longest = "a".repeat(name::MAX_NAME_LEN);
+    let created = env.cli(&[
+        "create-referral-account",
+        &longest,
+        "--partner",
+        &partner.to_string(),
+    ]);
+    let account = env
+        .client
+        .fetch_referral_account(pubkey(&created["referral_account"]))
+        .await
+        .unwrap();
+    assert_eq!(account.name, Some(longest));
+
+    // Unnamed account, at the address of a new keypair
+    let created = env.cli(&["create-referral-account"]);
+    let unnamed = pubkey(&created["referral_account"]);
+    let account = env.client.fetch_referral_account(unnamed).await.unwrap();
+    assert_eq!(account.name, None);
+    assert_eq!(account.partner, env.admin.pubkey());
+    assert_eq!(account.project, env.project);
+
+    // Fetching and listing
+    let fetched = env.cli(&["fetch-referral-account", &named.to_string()]);
+    assert_eq!(pubkey(&fetched["partner"]), partner);
+    assert_eq!(fetched["name"], "alice");
+    assert_eq!(fetched["share_bps"], DEFAULT_SHARE_BPS);
+
+    let listed = env.cli(&[
+        "list-referral-accounts",
+        "--project",
+        &env.project.to_string(),
+    ]);
+    let listed = listed["referral_accounts"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|account| pubkey(&account["address"]))
+        .collect::<HashSet<_>>();
+    assert_eq!(listed, HashSet::from([named, unnamed]));
+
+    let project = env.cli(&["fetch-project"]);
+    assert_eq!(pubkey(&project["admin"]), env.admin.pubkey());
+    assert_eq!(project["referral_accounts"], 2);
+
+    // Admin and partner updates
+    env.cli(&[
+        "update-referral-account",
+        "--referral-account",
+        &unnamed.to_string(),
+        "--share-bps",
+        "5000",
+    ]);
+    let account = env.client.fetch_referral_account(unnamed).await.unwrap();
+    assert_eq!(account.share_bps, 5_000);
+
+    let new_partner = Pubkey::new_unique();
+    env.cli(&[
+        "transfer-referral-account",
+        "--referral-account",
+        &unnamed.to_string(),
+        &new_partner.to_string(),
+    ]);
+    let account = env.client.fetch_referral_account(unnamed).await.unwrap();
+    assert_eq!(account.partner, new_partner);
+    assert_eq!(account.share_bps, 5_000);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs the referral program, run with --include-ignored"]
+async fn referral_token_accounts_in_legacy_transactions() {
+    let env = TestEnv::start().await;
+    let partner = Pubkey::new_unique();
+    let created = env.cli(&[
+        "create-referral-account",
+        "bob",
+        "--partner",
+        &partner.to_string(),
+    ]);
+    let referral_account = pubkey(&created["referral_account"]);
+
+    let mints = [
+        env.create_mint(spl_token_2022::ID).await,
+        env.create_mint(anchor_spl::token::ID).await,
+    ];
+    let mints_file = env.mints_file("mints.json", &mints);
+    let created = env.cli(&[
+        "create-referral-token-accounts",
+        "--referral-account",
+        &referral_account.to_string(),
+        &mints_file,
+    ]);
+    assert_eq!(created["created"].as_array().unwrap().len(), mints.len());
+    assert!(created["lookup_tables"].as_array().unwrap().is_empty());
+    for mint in mints {
+        let address = env
+            .client
+            .find_referral_token_account(referral_account, mint);
+        let (owner, token_account) = env.token_account(address).await;
+        let token_program = env.rpc_client.get_account(&mint).await.unwrap().owner;
+        assert_eq!(owner, token_program);
+        assert_eq!(token_account.mint, mint);
+    }
+
+    // Existing token accounts are skipped
+    let created = env.cli(&[
+        "create-referral-token-accounts",
+        "--referral-account",
+        &referral_account.to_string(),
+        &mints_file,
+    ]);
+    assert!(created["created"].as_array().unwrap().is_empty());
+    assert_eq!(created["skipped"].as_array().unwrap().len(), mints.len());
+
+    // A mint that does not exist is set aside without holding back the others
+    let mint = env.create_mint(anchor_spl::token::ID).await;
+    let missing_mint = Pubkey::new_unique();
+    let prepared = env
+        .client
+        .create_referral_token_accounts(
+            env.admin.pubkey(),
+            env.project,
+            referral_account,
+            &[mints[0], missing_mint, mint],
+        )
+        .await
+        .unwrap();
+    assert_eq!(prepared.existing, vec![mints[0]]);
+    assert_eq!(prepared.instructions.len(), 1);
+    assert_eq!(prepared.instructions[0].0, mint);
+    assert_eq!(prepared.invalid.len(), 1);
+    assert_eq!(prepared.invalid[0].0, missing_mint);
+
+    // Claiming empties the referral token account into the partner and admin accounts
+    let mint = mints[1];
+    let referral_token_account = env
+        .client
+        .find_referral_token_account(referral_account, mint);
+    env.send(
+        &[spl_token_2022::instruction::mint_to(
+            &anchor_spl::token::ID,
+            &mint,
+            &referral_token_account,
+            &env.admin.pubkey(),
+            &[],
+            1_000_000,
+        )
+        .unwrap()],
+        &[],
+    )
+    .await;
+    let claimed = env.cli(&[
+        "claim",
+        "--referral-account",
+        &referral_account.to_string(),
+        "--mint",
+        &mint.to_string(),
+    ]);
+    assert_eq!(claimed["claimed"].as_array().unwrap().len(), 1);
+    assert_eq!(env.token_balance(referral_token_account).await, 0);
+    let ata = |owner| {
+        anchor_spl::associated_token::get_associated_token_address_with_program_id(
+            &owner,
+            &mint,
+            &anchor_spl::token::ID,
+        )
+    };
+    let partner_amount = env.token_balance(ata(partner)).await;
+    let admin_amount = env.token_balance(ata(env.admin.pubkey())).await;
+    assert!(partner_amount > 0);
+    assert_eq!(partner_amount + admin_amount, 1_000_000);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs the referral program, run with --include-ignored"]
+async fn referral_token_accounts_through_a_lookup_table() {
+    let env = TestEnv::start().await;
+    let created = env.cli(&["create-referral-account", "carol"]);
+    let referral_account = pubkey(&created["referral_account"]);
+
+    // Enough mints that the instructions no longer fit a legacy transaction
+    let mut mints = vec![];
+    for _ in 0..6 {
+        mints.push(env.create_mint(anchor_spl::token::ID).await);
+    }
+    let mints_file = env.mints_file("mints.json", &mints);
+    let created = env.cli(&[
+        "create-referral-token-accounts",
+        "--referral-account",
+        &referral_account.to_string(),
+        &mints_file,
+    ]);
+    assert_eq!(created["created"].as_array().unwrap().len(), mints.len());
+    let lookup_tables = created["lookup_tables"].as_array().unwrap();
+    assert_eq!(lookup_tables.len(), 1);
+    let lookup_table = pubkey(&lookup_tables[0]);
+    assert_eq!(
+        utils::load_lookup_table_state(&env.path("lookup-tables.json")).unwrap(),
+        vec![lookup_table]
+    );
+
+    for mint in &mints {
+        let address = env
+            .client
+            .find_referral_token_account(referral_account, *mint);
+        assert_eq!(env.token_account(address).await.1.mint, *mint);
+    }
+    let table = utils::fetch_address_lookup_table(&env.rpc_client, lookup_table)
+        .await
+        .unwrap();
+    assert!(mints.iter().all(|mint| table.addresses.contains(mint)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs the referral program, run with --include-ignored"]
+async fn lookup_tables_are_created_and_reused() {
+    let env = TestEnv::start().await;
+    let accounts = (0..30)
+        .map(|_| Pubkey::new_unique())
+        .collect::<HashSet<_>>();
+
+    // More addresses than a single extension holds
+    let lookup_table = utils::create_and_extend_lookup_table(
+        &env.admin,
+        &env.rpc_client,
+        accounts.clone(),
+        None,
+        None,
+        &NO_COMPUTE_BUDGET,
+        &Silent,
+    )
+    .await
+    .unwrap();
+    let table = utils::fetch_address_lookup_table(&env.rpc_client, lookup_table)
+        .await
+        .unwrap();
+    assert_eq!(
+        table.addresses.iter().copied().collect::<HashSet<_>>(),
+        accounts
+    );
+
+    // A recorded table is extended with only the missing addresses
+    let state = env.path("lookup-tables.json");
+    utils::save_lookup_table_state(&state, &[lookup_table]).unwrap();
+    let mut more = accounts.iter().take(5).copied().collect::<HashSet<_>>();
+    let added = [Pubkey::new_unique(), Pubkey::new_unique()];
+    more.extend(added);
+    let table = utils::get_or_create_lookup_table(
+        &env.admin,
+        &env.rpc_client,
+        None,
+        &state,
+        more,
+        None,
+        &NO_COMPUTE_BUDGET,
+        &Silent,
+    )
+    .await
+    .unwrap();
+    assert_eq!(table.key, lookup_table);
+    assert_eq!(table.addresses.len(), accounts.len() + added.len());
+    assert!(added
+        .iter()
+        .all(|address| table.addresses.contains(address)));
+    assert_eq!(
+        utils::load_lookup_table_state(&state).unwrap(),
+        vec![lookup_table]
+    );
+
+    let tables = utils::fetch_lookup_tables_by_authority(&env.rpc_client, env.admin.pubkey())
+        .await
+        .unwrap();
+    assert_eq!(tables.len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs the referral program, run with --include-ignored"]
+async fn referral_accounts_from_a_manifest() {
+    let env = TestEnv::start().await;
+    let partner = Pubkey::new_unique();
+    let manifest = env.path("manifest.csv");
+    std::fs::write(
+        &manifest,
+        format!("name,partner,share_bps\ndave,{partner},\nerin,{partner},500\n"),
+    )
+    .unwrap();
+    let manifest = manifest.to_str().unwrap();
+
+    // A dry run creates nothing and writes no results
+    let simulated = env.cli(&[
+        "--dry-run",
+        "create-referral-accounts",
+        "--manifest",
+        manifest,
+    ]);
+    assert_eq!(simulated["dry_run"], true);
+    assert_eq!(simulated["results"].as_array().unwrap().len(), 2);
+    let dave = env
+        .client
+        .find_referral_account(env.project, "dave")
+        .unwrap();
+    let erin = env
+        .client
+        .find_referral_account(env.project, "erin")
+        .unwrap();
+    assert!(env.rpc_client.get_account(&dave).await.is_err());
+    assert!(!env.path("manifest.results.json").exists());
+
+    let created = env.cli(&["create-referral-accounts", "--manifest", manifest]);
+    let results = created["results"].as_array().unwrap();
+    assert_eq!(results[0]["status"], "created");
+    assert_eq!(results[1]["status"], "created");
+    assert_eq!(pubkey(&results[1]["referral_account"]), erin);
+    assert_eq!(results[1]["share_bps"], 500);
+    assert!(results[1]["share_signature"].is_string());
+    let account = env.client.fetch_referral_account(dave).await.unwrap();
+    assert_eq!(account.partner, partner);
+    assert_eq!(account.share_bps, DEFAULT_SHARE_BPS);
+    let account = env.client.fetch_referral_account(erin).await.unwrap();
+    assert_eq!(account.share_bps, 500);
+    assert!(env.path("manifest.results.json").is_file());
+
+    // Existing accounts are skipped
+    let rerun = env.cli(&["create-referral-accounts", "--manifest", manifest]);
+    assert!(rerun["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|result| result["status"] == "exists"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs the referral program, run with --include-ignored"]
+async fn fees_are_reported_and_claimed() {
+    let env = TestEnv::start().await;
+    let partner = Pubkey::new_unique();
+    let created = env.cli(&[
+        "create-referral-account",
+        "frank",
+        "--partner",
+        &partner.to_string(),
+    ]);
+    let referral_account = pubkey(&created["referral_account"]);
+    let mints = [
+        env.create_mint(anchor_spl::token::ID).await,
+        env.create_mint(anchor_spl::token::ID).await,
+    ];
+    let mints_file = env.mints_file("mints.json", &mints);
+    env.cli(&[
+        "create-referral-token-accounts",
+        "--referral-account",
+        &referral_account.to_string(),
+        &mints_file,
+    ]);
+    let token_accounts = mints.map(|mint| {
+        env.client
+            .find_referral_token_account(referral_account, mint)
+    });
+    env.mint_to(mints[0], token_accounts[0], 1_000_000).await;
+    env.mint_to(mints[1], token_accounts[1], 500_000).await;
+
+    let fetched = env.cli(&[
+        "fetch-referral-account",
+        &referral_account.to_string(),
+        "--with-balances",
+    ]);
+    let mut balances = fetched["balances"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|balance| (pubkey(&balance["mint"]), balance["amount"].clone()))
+        .collect::<Vec<_>>();
+    balances.sort_by_key(|(mint, _)| mints.iter().position(|other| other == mint));
+    assert_eq!(
+        balances,
+        vec![
+            (mints[0], Value::from("1000000")),
+            (mints[1], Value::from("500000"))
+        ]
+    );
+
+    // Only the first mint is priced, at 2 USD for 1.0 token
+    let prices = env.path("prices.json");
+    std::fs::write(&prices, format!("{{\"{}\": 2.0}}", mints[0])).unwrap();
+    let report = env.cli(&[
+        "report",
+        "--referral-account",
+        &referral_account.to_string(),
+        "--prices",
+        prices.to_str().unwrap(),
+    ]);
+    assert_eq!(report["mints"].as_array().unwrap().len(), 2);
+    assert_eq!(report["unpriced"], Value::from(vec![mints[1].to_string()]));
+    assert_eq!(report["total_usd"], 2.0);
+
+    // A dry run leaves the fees in place
+    let simulated = env.cli(&[
+        "--dry-run",
+        "claim-all",
+        "--referral-account",
+        &referral_account.to_string(),
+    ]);
+    assert_eq!(simulated["dry_run"], true);
+    assert_eq!(simulated["claimed"].as_array().unwrap().len(), 2);
+    let simulations = simulated["simulations"].as_array().unwrap();
+    assert!(!simulations.is_empty());
+    assert!(simulations
+        .iter()
+        .all(|simulation| simulation["error"].is_null()));
+    assert_eq!(env.token_balance(token_accounts[0]).await, 1_000_000);
+
+    let claimed = env.cli(&[
+        "claim-all",
+        "--referral-account",
+        &referral_account.to_string(),
+    ]);
+    assert_eq!(claimed["claimed"].as_array().unwrap().len(), 2);
+    assert!(claimed["failed"].as_array().unwrap().is_empty());
+    // Both claims fit in one legacy transaction
+    assert_eq!(claimed["signatures"].as_array().unwrap().len(), 1);
+    assert!(claimed["lookup_tables"].as_array().unwrap().is_empty());
+    for token_account in token_accounts {
+        assert_eq!(env.token_balance(token_account).await, 0);
+    }
+
+    let claimed = env.cli(&[
+        "claim-all",
+        "--referral-account",
+        &referral_account.to_string(),
+    ]);
+    assert!(claimed["claimed"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs the referral program, run with --include-ignored"]
+async fn transactions_signed_offline_with_a_durable_nonce() {
+    let env = TestEnv::start().await;
+    let partner = Keypair::new();
+    let partner_file = env.path("partner.json");
+    write_keypair_file(&partner, &partner_file).unwrap();
+    let created = env.cli(&[
+        "create-referral-account",
+        "--partner",
+        &partner.pubkey().to_string(),
+    ]);
+    let referral_account = pubkey(&created["referral_account"]);
+
+    let nonce = env.cli(&["nonce", "create"]);
+    let nonce_account = pubkey(&nonce["nonce_account"]);
+    assert_eq!(pubkey(&nonce["authority"]), env.admin.pubkey());
+    assert!(env.rpc_client.get_account(&nonce_account).await.is_ok());
+
+    // The admin pays and signs for the nonce, the partner signs later
+    let new_partner = Pubkey::new_unique();
+    let exported = env.cli(&[
+        "--sign-only",
+        "--nonce",
+        &nonce_account.to_string(),
+        "transfer-referral-account",
+        "--referral-account",
+        &referral_account.to_string(),
+        "--partner",
+        &partner.pubkey().to_string(),
+        &new_partner.to_string(),
+    ]);
+    let transaction = &exported["transactions"][0];
+    assert_eq!(
+        transaction["missing_signers"],
+        Value::from(vec![partner.pubkey().to_string()])
+    );
+    let account = env
+        .client
+        .fetch_referral_account(referral_account)
+        .await
+        .unwrap();
+    assert_eq!(account.partner, partner.pubkey());
+
+    let signed = env.cli(&[
+        "sign",
+        transaction["transaction"].as_str().unwrap(),
+        "--signer",
+        partner_file.to_str().unwrap(),
+    ]);
+    let transaction = &signed["transactions"][0];
+    assert!(transaction["missing_signers"]
+        .as_array()
+        .unwrap()
+        .is_empty());
+
+    let broadcast = env.cli(&["broadcast", transaction["transaction"].as_str().unwrap()]);
+    assert!(broadcast["signature"].is_string());
+    let account = env
+        .client
+        .fetch_referral_account(referral_account)
+        .await
+        .unwrap();
+    assert_eq!(account.partner, new_partner);
+
+    // The nonce covers a single transaction, so signing a second one with it fails
+    let manifest = env.path("manifest.csv");
+    std::fs::write(&manifest, format!("carol,{},500\n", partner.pubkey())).unwrap();
+    let error = env.cli_error(&[
+        "--sign-only",
+        "--nonce",
+        &nonce_account.to_string(),
+        "create-referral-accounts",
+        "--manifest",
+        manifest.to_str().unwrap(),
+    ]);
+    assert!(error.contains("already used"), "{}", error);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs the referral program, run with --include-ignored"]
+async fn lookup_tables_are_managed_through_the_cli() {
+    let env = TestEnv::start().await;
+    let accounts = (0..3).map(|_| Pubkey::new_unique()).collect::<HashSet<_>>();
+    let lookup_table = utils::create_and_extend_lookup_table(
+        &env.admin,
+        &env.rpc_client,
+        accounts.clone(),
+        None,
+        None,
+        &NO_COMPUTE_BUDGET,
+        &Silent,
+    )
+    .await
+    .unwrap();
+    utils::save_lookup_table_state(&env.path("lookup-tables.json"), &[lookup_table]).unwrap();
+
+    let listed = env.cli(&["lut", "list"]);
+    let tables = listed["lookup_tables"].as_array().unwrap();
+    assert_eq!(tables.len(), 1);
+    assert_eq!(pubkey(&tables[0]["address"]), lookup_table);
+    assert_eq!(tables[0]["addresses"], accounts.len());
+    assert_eq!(tables[0]["saved"], true);
+    assert_eq!(tables[0]["status"], "active");
+
+    let shown = env.cli(&["lut", "show", &lookup_table.to_string()]);
+    let shown = shown["lookup_tables"][0]["addresses"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(pubkey)
+        .collect::<HashSet<_>>();
+    assert_eq!(shown, accounts);
+
+    let deactivated = env.cli(&["lut", "deactivate", &lookup_table.to_string()]);
+    assert_eq!(
+        deactivated["deactivated"],
+        Value::from(vec![lookup_table.to_string()])
+    );
+    let listed = env.cli(&["lut", "list"]);
+    assert_eq!(listed["lookup_tables"][0]["status"], "deactivating");
+
+    // Deactivated tables cool down for about 500 slots before they can be closed
+    let closed = env.cli(&["lut", "close", &lookup_table.to_string()]);
+    assert!(closed["closed"].as_array().unwrap().is_empty());
+    assert_eq!(
+        closed["skipped"],
+        Value::from(vec![lookup_table.to_string()])
+    );
+    assert!(env.rpc_client.get_account(&lookup_table).await.is_ok());
+}